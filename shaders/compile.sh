@@ -0,0 +1,6 @@
+#!/bin/sh
+# Rebuilds the SPIR-V binaries that are embedded with include_bytes!.
+cd "$(dirname "$0")"
+glslc shader.vert -o vert.spv
+glslc shader.frag -o frag.spv
+glslc shader.comp -o comp.spv
@@ -0,0 +1,1535 @@
+//! The winit application handler and the renderer it drives.
+
+use anyhow::{anyhow, Context, Result};
+
+use cgmath::{Matrix4, SquareMatrix, Vector3, Zero};
+
+use log::*;
+
+use winit::application::ApplicationHandler;
+use winit::dpi::{LogicalSize, PhysicalPosition, PhysicalSize};
+use winit::event::{self, DeviceEvent, ElementState, WindowEvent};
+use winit::keyboard::*;
+use winit::event_loop::{ActiveEventLoop, ControlFlow};
+use winit::window::{self, CursorGrabMode, Fullscreen, Window};
+
+use vulkanalia::loader::{LibloadingLoader, LIBRARY};
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::vk::KhrSwapchainExtension;
+use vulkanalia::window as vk_window;
+use vulkanalia::Version;
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::AppConfig;
+use crate::instance::{set_debug_name, destroy_instance_objects, create_instance, PortabilityMode};
+use crate::device::{RequiredFeatures, log_queue_families, pick_physical_device, log_subgroup_properties, create_logical_decice};
+use crate::swapchain::{SwapchainAccess, SwapchainSupport, create_swapchain, create_offscreen_target, create_color_objects, create_swapchain_image_views, vsync_off_mode, PresentMode};
+use crate::pipeline::{EntryPoints, VERT_SHADER_PATH, FRAG_SHADER_PATH, final_layout, create_render_pass, create_pipeline_cache, save_pipeline_cache, create_pipeline, build_pipeline};
+use crate::memory::{MemoryLocation, Allocation, MemoryAllocator};
+use crate::commands::{MAX_FRAMES_IN_FLIGHT, CLEAR_COLOR, create_framebuffers, create_query_pool, timestamp_delta_ms, create_command_pool, create_command_buffers, record_command_buffer, animated_clear_color, create_sync_objects, DrawStats, CommandRecorder};
+use crate::handles::{track_created, track_destroyed};
+use crate::camera::{Camera, CameraControls, camera_step};
+use crate::input::InputState;
+use crate::signal::interrupted;
+use crate::model::{create_model_buffers, destroy_model_buffers};
+use crate::offscreen::{OffscreenTarget, create_render_target, destroy_render_target};
+use crate::descriptors::{UniformBufferObject, create_descriptor_set_layout, create_uniform_buffers, create_descriptor_pool, create_descriptor_sets, write_uniform_buffer, destroy_uniform_objects};
+
+#[derive(Default ,Debug)]
+pub struct App {
+    pub window: Option<Window>,
+    pub app: Option<VulkanApp>,
+    pub scale_factor: f64,
+    // on-demand rendering, from `AppConfig::idle`
+    pub idle: bool,
+    pub needs_redraw: bool,
+    // ignore the saved window geometry, from `AppConfig::reset_window`
+    pub reset_window: bool,
+    pub config: AppConfig,
+    // borderless fullscreen, toggled with F11
+    pub fullscreen: bool,
+    // rebuild the pipeline when the SPIR-V files change (--hot-reload, debug builds only)
+    pub shader_watch: Option<ShaderWatch>,
+    // why the window or renderer couldn't be created; the event loop exits
+    // and main reports failure
+    pub init_error: Option<anyhow::Error>,
+    // keys held and pressed since the last event loop iteration
+    pub input: InputState,
+    // movement keys and mouse look feeding `VulkanApp::camera`
+    pub camera_controls: CameraControls,
+    // cursor grabbed and hidden for mouse look, toggled with Tab
+    pub cursor_captured: bool,
+    // holds back redraws to `AppConfig::max_fps` (--max-fps)
+    pub frame_limiter: Option<FrameLimiter>
+}
+
+/// Polls the modification times of the shader files for hot-reloading.
+#[derive(Debug)]
+pub struct ShaderWatch {
+    pub paths: Vec<PathBuf>,
+    pub modified: Vec<Option<SystemTime>>
+}
+
+impl ShaderWatch {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        let modified = paths.iter().map(|p| Self::modified_time(p)).collect();
+        return Self { paths, modified };
+    }
+
+    pub fn modified_time(path: &Path) -> Option<SystemTime> {
+        return std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    }
+
+    /// Whether any file changed since the last call.
+    pub fn changed(&mut self) -> bool {
+        let modified = self.paths.iter().map(|p| Self::modified_time(p)).collect::<Vec<_>>();
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        return true;
+    }
+}
+
+// sleeping can overshoot by about a scheduler tick, so the last stretch
+// before a frame deadline is spun instead
+pub const FRAME_LIMITER_SPIN: Duration = Duration::from_millis(2);
+
+/// Paces frames to a fixed rate, independently of the present mode.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameLimiter {
+    pub frame_time: Duration,
+    // when the next frame may start
+    pub deadline: Instant
+}
+
+impl FrameLimiter {
+    pub fn new(max_fps: u32) -> Self {
+        return Self { frame_time: Duration::from_secs(1) / max_fps.max(1), deadline: Instant::now() };
+    }
+
+    /// Blocks until the current frame has taken up its share of time: sleeps
+    /// until shortly before the deadline, then spins the rest.
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        if let Some(sleep) = self.deadline.checked_duration_since(now + FRAME_LIMITER_SPIN) {
+            std::thread::sleep(sleep);
+        }
+        while Instant::now() < self.deadline {
+            std::hint::spin_loop();
+        }
+        self.deadline = Self::next_deadline(self.deadline, self.frame_time, Instant::now());
+    }
+
+    /// Deadlines advance by whole frames so the rate doesn't drift, except
+    /// after a frame ran long, where catching up would release a burst of
+    /// frames; then the next one is a full frame from now.
+    pub fn next_deadline(deadline: Instant, frame_time: Duration, now: Instant) -> Instant {
+        let next = deadline + frame_time;
+        return if next < now { now + frame_time } else { next };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VulkanApp {
+    pub entry: Entry,
+    pub instance: Instance,
+    pub data: AppData,
+    pub device: Device,
+    pub frame: usize,
+    // set when the window size changed so the next frame recreates the swapchain
+    pub resized: bool,
+    // frames presented since the title's FPS readout was last updated
+    pub frame_count: u32,
+    pub title_updated: Instant,
+    // swapchain image presented last, for headless readback
+    pub last_image: Option<usize>,
+    // where to save the next windowed frame, captured before it's presented
+    pub screenshot_request: Option<PathBuf>,
+    // window title the FPS readout is appended to
+    pub title: String,
+    // GPU time of the most recently measured render pass, if timestamps are supported
+    pub gpu_frame_time: Option<f64>,
+    // drives the clear color animation
+    pub started: Instant,
+    // set once the logical device and everything created from it is gone,
+    // between a device loss and a successful `recreate_device`
+    pub device_destroyed: bool,
+    pub camera: Camera,
+    // when the camera last moved, for frame-rate independent movement
+    pub camera_updated: Instant,
+    // work submitted since the FPS readout was last updated
+    pub draw_stats: DrawStats,
+}
+
+impl VulkanApp {
+    /// Creates the renderer for `window`, or for an offscreen target of
+    /// `config.headless` size when there is no window, on a device supporting
+    /// every feature in `required_features`.
+    pub fn create(window: Option<&Window>, config: &AppConfig, required_features: RequiredFeatures) -> Result<Self> {
+
+        let loader: LibloadingLoader;
+        unsafe {
+            loader = LibloadingLoader::new(LIBRARY)?;
+        }
+
+        let entry: Entry;
+        unsafe {
+            entry = Entry::new(loader).map_err(|b| anyhow!("{}", b))?;
+        }
+        let mut data = AppData {
+            queue_priority: config.queue_priority,
+            present_mode: config.present_mode,
+            validation: config.validation,
+            headless: config.headless.filter(|_| window.is_none()),
+            clear_color: CLEAR_COLOR,
+            animate_clear: config.animate_clear,
+            forced_gpu: config.gpu,
+            device_recoveries: config.device_recoveries,
+            model_path: config.model.clone(),
+            msaa_request: config.msaa,
+            object_transforms: vec![Matrix4::identity()],
+            required_features,
+            entry_points: config.entry_points.clone(),
+            ..Default::default()
+        };
+        let instance = create_instance(window, &entry, &config.identity, &mut data)?;
+
+        // Every step after the instance exists releases what was already
+        // created when it fails; leaked instances hang some drivers on exit.
+        let device = match Self::create_device(window, &entry, &instance, &mut data) {
+            Ok(device) => device,
+            Err(error) => {
+                unsafe {
+                    destroy_instance_objects(&instance, &mut data);
+                }
+                return Err(error);
+            }
+        };
+
+        let mut app = Self {entry, instance, data, device, frame: 0, resized: false, frame_count: 0, title_updated: Instant::now(), last_image: None, screenshot_request: None, title: config.title.clone(), gpu_frame_time: None, started: Instant::now(), device_destroyed: false, camera: Camera::default(), camera_updated: Instant::now(), draw_stats: DrawStats::default()};
+        unsafe {
+            if let Err(error) = app.create_device_objects().and_then(|_| app.create_swapchain_objects(window)) {
+                app.destroy();
+                return Err(error);
+            }
+        }
+
+        return Ok(app)
+    }
+
+    /// Creates the device-level objects that don't depend on the window size.
+    pub unsafe fn create_device_objects(&mut self) -> Result<()> {
+        create_command_pool(&self.instance, &self.device, &mut self.data)?;
+        create_sync_objects(&self.device, &mut self.data)?;
+        create_descriptor_set_layout(&self.device, &mut self.data)?;
+        create_model_buffers(&self.device, &mut self.data)?;
+        create_pipeline_cache(&self.instance, &self.device, &mut self.data)?;
+        return Ok(());
+    }
+
+    pub fn create_device(window: Option<&Window>, entry: &Entry, instance: &Instance, data: &mut AppData) -> Result<Device> {
+        if let Some(window) = window {
+            unsafe {
+                data.surface = vk_window::create_surface(instance, &window, &window)?;
+            }
+            track_created("SurfaceKHR");
+        }
+        unsafe {
+            pick_physical_device(instance, data)?;
+            log_subgroup_properties(instance, data);
+            log_queue_families(instance, data)?;
+        }
+        let device = create_logical_decice(entry, instance, data)?;
+        data.allocator = unsafe { MemoryAllocator::new(instance, data.physical_device) };
+        return Ok(device);
+    }
+
+    pub unsafe fn create_swapchain_objects(&mut self, window: Option<&Window>) -> Result<()> {
+        let previous_format = self.data.swapchain_format;
+        match window {
+            Some(window) => create_swapchain(window, &self.instance, &self.device, &mut self.data)?,
+            None => create_offscreen_target(&self.device, &mut self.data)?,
+        }
+        create_swapchain_image_views(&self.device, &mut self.data)?;
+        create_color_objects(&self.device, &mut self.data)?;
+        create_render_target(&self.device, &mut self.data)?;
+
+        // With dynamic viewport and scissor the render pass and pipeline only
+        // depend on the swapchain format, so a resize keeps them and costs
+        // framebuffers and command buffers only. A format change (e.g. the
+        // window moving to another monitor) still needs new ones.
+        if self.data.swapchain_format != previous_format {
+            self.destroy_pipeline_objects();
+        }
+        if self.data.render_pass.is_null() {
+            create_render_pass(&self.instance, &self.device, &mut self.data)?;
+            create_pipeline(&self.device, &mut self.data)?;
+        }
+
+        create_framebuffers(&self.device, &mut self.data)?;
+        create_query_pool(&self.instance, &self.device, &mut self.data)?;
+        create_uniform_buffers(&self.device, &mut self.data)?;
+        create_descriptor_pool(&self.device, &mut self.data)?;
+        create_descriptor_sets(&self.device, &mut self.data)?;
+        create_command_buffers(&self.device, &mut self.data)?;
+        self.data.images_in_flight = vec![vk::Fence::null(); self.data.swapchain_images.len()];
+        self.name_objects();
+        return Ok(());
+    }
+
+    /// Names the long-lived objects for validation messages. Handles are
+    /// replaced on swapchain recreation and shader reloads, so this runs after
+    /// each of those.
+    pub unsafe fn name_objects(&self) {
+        let (instance, device, data) = (&self.instance, &self.device, &self.data);
+        set_debug_name(instance, device, data, instance.handle(), "instance");
+        set_debug_name(instance, device, data, device.handle(), "device");
+        set_debug_name(instance, device, data, data.graphics_queue, "graphics queue");
+        if data.present_queue != data.graphics_queue {
+            set_debug_name(instance, device, data, data.present_queue, "present queue");
+        }
+        if data.transfer_queue != data.graphics_queue {
+            set_debug_name(instance, device, data, data.transfer_queue, "transfer queue");
+        }
+        set_debug_name(instance, device, data, data.swapchain, "swapchain");
+        set_debug_name(instance, device, data, data.render_pass, "render pass");
+        set_debug_name(instance, device, data, data.pipeline, "triangle pipeline");
+        set_debug_name(instance, device, data, data.wireframe_pipeline, "wireframe pipeline");
+        for (i, &command_buffer) in data.command_buffers.iter().enumerate() {
+            set_debug_name(instance, device, data, command_buffer, &format!("command buffer {}", i));
+        }
+    }
+
+    /// Renders a frame, recreating the device first if it was lost.
+    pub unsafe fn render(&mut self, window: &Window) -> Result<()> {
+        return match self.render_frame(window) {
+            Err(error) if is_device_lost(&error) => self.recover_device_lost(window),
+            result => result,
+        };
+    }
+
+    pub unsafe fn render_frame(&mut self, window: &Window) -> Result<()> {
+
+        // a minimized window has a zero-sized surface, which can't back a
+        // swapchain; just skip frames until it comes back
+        let size = window.inner_size();
+        if size.width == 0 || size.height == 0 {
+            return Ok(());
+        }
+
+        let in_flight_fence = self.data.in_flight_fences[self.frame];
+        self.device.wait_for_fences(&[in_flight_fence], true, u64::MAX)?;
+
+        let result = self.device
+        .acquire_next_image_khr(self.data.swapchain, u64::MAX, self.data.image_available_semaphores[self.frame], vk::Fence::null());
+
+        let image_index = match result {
+            Ok((image_index, _)) => image_index as usize,
+            Err(vk::ErrorCode::OUT_OF_DATE_KHR) => return self.recreate_swapchain(window),
+            Err(error) => return Err(anyhow!(error)),
+        };
+
+        // the acquired image may still be used by an older frame; its command
+        // buffer can't be resubmitted until that frame finishes
+        let image_in_flight = self.data.images_in_flight[image_index];
+        if !image_in_flight.is_null() {
+            self.device.wait_for_fences(&[image_in_flight], true, u64::MAX)?;
+            // that frame is done, so its timestamps are available
+            self.read_gpu_frame_time(image_index)?;
+        }
+        self.data.images_in_flight[image_index] = in_flight_fence;
+
+        self.update_uniform_buffer(image_index);
+        if self.data.animate_clear {
+            self.data.clear_color = animated_clear_color(self.started.elapsed().as_secs_f32());
+        }
+        self.update_command_buffer(image_index)?;
+
+        let wait_semaphores = &[self.data.image_available_semaphores[self.frame]];
+        let wait_stages = &[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+        let command_buffers = &[self.data.command_buffers[image_index]];
+        let signal_semaphores = &[self.data.render_finished_semaphores[self.frame]];
+        let submit_info = vk::SubmitInfo::builder()
+        .wait_semaphores(wait_semaphores)
+        .wait_dst_stage_mask(wait_stages)
+        .command_buffers(command_buffers)
+        .signal_semaphores(signal_semaphores);
+
+        self.device.reset_fences(&[in_flight_fence])?;
+        self.device.queue_submit(self.data.graphics_queue, &[submit_info], in_flight_fence)?;
+        self.draw_stats.add(self.data.recorded_stats[image_index]);
+
+        // the image is still ours until it's presented; once it is, reading
+        // it back isn't allowed until it's acquired again
+        if let Some(path) = self.screenshot_request.take() {
+            match self.capture_screenshot(self.data.swapchain_images[image_index], &path) {
+                Ok(()) => info!("Saved screenshot to {}.", path.display()),
+                Err(error) => error!("Failed to save screenshot: {:#}", error),
+            }
+        }
+
+        let swapchains = &[self.data.swapchain];
+        let image_indices = &[image_index as u32];
+        let present_info = vk::PresentInfoKHR::builder()
+        .wait_semaphores(signal_semaphores)
+        .swapchains(swapchains)
+        .image_indices(image_indices);
+
+        let result = self.device.queue_present_khr(self.data.present_queue, &present_info);
+        let changed = result == Ok(vk::SuccessCode::SUBOPTIMAL_KHR) || result == Err(vk::ErrorCode::OUT_OF_DATE_KHR);
+
+        self.frame = (self.frame + 1) % MAX_FRAMES_IN_FLIGHT;
+        self.last_image = Some(image_index);
+        self.update_frame_stats(window);
+
+        if self.resized || changed {
+            self.recreate_swapchain(window)?;
+        } else if let Err(error) = result {
+            return Err(anyhow!(error));
+        }
+
+        return Ok(());
+    }
+
+    /// Renders one frame into the offscreen target of a headless app and
+    /// waits for it; read it back with `read_frame`.
+    pub unsafe fn render_headless(&mut self) -> Result<()> {
+
+        let in_flight_fence = self.data.in_flight_fences[self.frame];
+        self.device.wait_for_fences(&[in_flight_fence], true, u64::MAX)?;
+        self.update_uniform_buffer(0);
+        self.update_command_buffer(0)?;
+
+        let command_buffers = &[self.data.command_buffers[0]];
+        let submit_info = vk::SubmitInfo::builder()
+        .command_buffers(command_buffers);
+
+        self.device.reset_fences(&[in_flight_fence])?;
+        self.device.queue_submit(self.data.graphics_queue, &[submit_info], in_flight_fence)?;
+        self.draw_stats.add(self.data.recorded_stats[0]);
+        self.device.wait_for_fences(&[in_flight_fence], true, u64::MAX)?;
+        self.read_gpu_frame_time(0)?;
+
+        self.frame = (self.frame + 1) % MAX_FRAMES_IN_FLIGHT;
+        self.last_image = Some(0);
+
+        return Ok(());
+    }
+
+    /// Moves the camera by the held keys and the mouse movement since the
+    /// last call.
+    pub fn update_camera(&mut self, controls: &mut CameraControls) {
+        let now = Instant::now();
+        let dt = camera_step(now.duration_since(self.camera_updated));
+        self.camera_updated = now;
+        self.camera.update(controls.movement(), controls.take_look(), dt);
+    }
+
+    /// Writes the model and camera matrices for the frame rendered to
+    /// swapchain image `image_index`, using the current extent so resizes
+    /// keep the aspect.
+    pub unsafe fn update_uniform_buffer(&self, image_index: usize) {
+        let extent = self.data.swapchain_extent;
+        let ubo = UniformBufferObject {
+            model: Matrix4::identity(),
+            view: self.camera.view_matrix(),
+            proj: self.camera.projection_matrix(extent.width as f32 / extent.height as f32),
+        };
+        write_uniform_buffer(&self.data, image_index, &ubo);
+    }
+
+    /// Re-records the command buffer of a swapchain image if it was recorded
+    /// with a different clear color. The image's previous frame must be done.
+    pub unsafe fn update_command_buffer(&mut self, image_index: usize) -> Result<()> {
+        if self.data.recorded_clear_colors[image_index] != self.data.clear_color {
+            record_command_buffer(&self.device, &mut self.data, image_index)?;
+        }
+        return Ok(());
+    }
+
+    /// Reads the render pass timestamps last written by the command buffer of
+    /// the given swapchain image.
+    pub unsafe fn read_gpu_frame_time(&mut self, image_index: usize) -> Result<()> {
+        if self.data.query_pool.is_null() {
+            return Ok(());
+        }
+
+        let mut timestamps = [0u64; 2];
+        let result = self.device.get_query_pool_results(
+            self.data.query_pool,
+            2 * image_index as u32,
+            2,
+            std::slice::from_raw_parts_mut(timestamps.as_mut_ptr() as *mut u8, std::mem::size_of_val(&timestamps)),
+            std::mem::size_of::<u64>() as vk::DeviceSize,
+            vk::QueryResultFlags::_64,
+        )?;
+
+        // NOT_READY leaves the previous measurement in place
+        if result == vk::SuccessCode::SUCCESS {
+            self.gpu_frame_time = timestamp_delta_ms(timestamps[0], timestamps[1], self.data.timestamp_period, self.data.timestamp_valid_bits);
+        }
+        return Ok(());
+    }
+
+    /// Counts a presented frame and, about once a second, shows the average
+    /// FPS and frame time in the window title.
+    pub fn update_frame_stats(&mut self, window: &Window) {
+        self.frame_count += 1;
+
+        let elapsed = self.title_updated.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let fps = self.frame_count as f64 / elapsed.as_secs_f64();
+            let frame_time = elapsed.as_secs_f64() * 1000.0 / self.frame_count as f64;
+            let gpu_time = self.gpu_frame_time.map(|t| format!(", GPU {:.2} ms", t)).unwrap_or_default();
+            window.set_title(&format!("{} \u{2014} {:.0} FPS ({:.1} ms{})", self.title, fps, frame_time, gpu_time));
+            debug!("{:.0} FPS, {}.", fps, self.draw_stats.per_frame(self.frame_count));
+
+            self.frame_count = 0;
+            self.draw_stats = DrawStats::default();
+            self.title_updated = Instant::now();
+        }
+    }
+
+    /// Rebuilds the pipeline from the SPIR-V files on disk. The old pipeline
+    /// is only replaced once the new one was built successfully.
+    pub unsafe fn reload_shaders(&mut self) -> Result<()> {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let vert = std::fs::read(root.join(VERT_SHADER_PATH)).with_context(|| format!("Failed to read {}", VERT_SHADER_PATH))?;
+        let frag = std::fs::read(root.join(FRAG_SHADER_PATH)).with_context(|| format!("Failed to read {}", FRAG_SHADER_PATH))?;
+
+        let (layout, pipeline, wireframe_pipeline) = build_pipeline(&self.device, &self.data, &vert, &frag)?;
+
+        self.wait_idle();
+        self.destroy_pipelines();
+        self.data.pipeline_layout = layout;
+        self.data.pipeline = pipeline;
+        self.data.wireframe_pipeline = wireframe_pipeline;
+
+        // the recorded command buffers bind the old pipeline
+        self.device.free_command_buffers(self.data.command_pool, &self.data.command_buffers);
+        self.data.command_buffers.clear();
+        create_command_buffers(&self.device, &mut self.data)?;
+        self.name_objects();
+
+        return Ok(());
+    }
+
+    /// Switches between filled and wireframe rendering. Without
+    /// fillModeNonSolid there is no wireframe pipeline and this does nothing.
+    pub unsafe fn set_wireframe(&mut self, wireframe: bool) -> Result<()> {
+        if self.data.wireframe_pipeline.is_null() {
+            warn!("Wireframe rendering is not supported on this device.");
+            return Ok(());
+        }
+
+        self.wait_idle();
+        self.data.wireframe = wireframe;
+        for i in 0..self.data.command_buffers.len() {
+            record_command_buffer(&self.device, &mut self.data, i)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Replaces a lost device with a new one on the same physical device,
+    /// trying up to `AppData::device_recoveries` times. The instance and surface
+    /// survive a device loss; everything created from the device doesn't.
+    pub unsafe fn recover_device_lost(&mut self, window: &Window) -> Result<()> {
+        warn!("The device was lost; recreating it.");
+        for attempt in 1..=self.data.device_recoveries {
+            match self.recreate_device(window) {
+                Ok(()) => {
+                    info!("Recreated the device after {} attempt(s).", attempt);
+                    return Ok(());
+                }
+                Err(error) => warn!("Device recreation attempt {}/{} failed: {:#}", attempt, self.data.device_recoveries, error),
+            }
+        }
+        error!("Giving up on the lost device.");
+        return Err(anyhow!(vk::ErrorCode::DEVICE_LOST));
+    }
+
+    pub unsafe fn recreate_device(&mut self, window: &Window) -> Result<()> {
+        self.destroy_device_objects(false);
+        self.frame = 0;
+        self.last_image = None;
+        self.gpu_frame_time = None;
+        self.device = create_logical_decice(&self.entry, &self.instance, &mut self.data)?;
+        self.device_destroyed = false;
+        self.data.allocator = MemoryAllocator::new(&self.instance, self.data.physical_device);
+        self.create_device_objects()?;
+        return self.create_swapchain_objects(Some(window));
+    }
+
+    /// Rebuilds everything that depends on the swapchain extent or format,
+    /// e.g. after a resize.
+    pub unsafe fn recreate_swapchain(&mut self, window: &Window) -> Result<()> {
+        self.wait_idle();
+        self.resized = false;
+        self.last_image = None;
+        self.destroy_swapchain_objects();
+        self.create_swapchain_objects(Some(window))?;
+        return Ok(());
+    }
+
+    /// Switches between FIFO (vsync) and the fastest other present mode the
+    /// surface supports. The swapchain is recreated with it on the next frame.
+    pub unsafe fn toggle_vsync(&mut self) -> Result<()> {
+        let mode = match self.data.present_mode {
+            PresentMode::Fifo => {
+                let support = SwapchainSupport::get(&self.instance, &self.data, self.data.physical_device)?;
+                match vsync_off_mode(&support.present_modes) {
+                    Some(mode) => mode,
+                    None => {
+                        warn!("The surface only supports FIFO; vsync stays on.");
+                        return Ok(());
+                    }
+                }
+            }
+            PresentMode::Mailbox | PresentMode::Immediate => PresentMode::Fifo,
+        };
+        info!("Switching present mode from {:?} to {:?}.", self.data.present_mode, mode);
+        self.data.present_mode = mode;
+        self.resized = true;
+        return Ok(());
+    }
+
+    /// Saves the next rendered frame to `path` from within `render_frame`.
+    pub fn request_screenshot(&mut self, path: PathBuf) {
+        self.screenshot_request = Some(path);
+    }
+
+    /// Writes the last frame of a headless app to `path` as an RGBA8 PNG.
+    pub unsafe fn save_screenshot(&mut self, path: &Path) -> Result<()> {
+        let pixels = self.read_frame()?;
+        return self.write_png(path, &pixels);
+    }
+
+    /// Writes `source`, an image rendered but not yet presented, to `path`.
+    pub unsafe fn capture_screenshot(&mut self, source: vk::Image, path: &Path) -> Result<()> {
+        let pixels = self.read_image(source)?;
+        return self.write_png(path, &pixels);
+    }
+
+    pub fn write_png(&self, path: &Path, pixels: &[u8]) -> Result<()> {
+
+        let extent = self.data.swapchain_extent;
+
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), extent.width, extent.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(pixels)?;
+
+        return Ok(());
+    }
+
+    /// Reads back the last frame of a headless app as tightly packed RGBA8
+    /// rows. A presented swapchain image belongs to the presentation engine,
+    /// so windowed apps capture with `request_screenshot` instead.
+    pub unsafe fn read_frame(&mut self) -> Result<Vec<u8>> {
+
+        if !self.data.swapchain.is_null() {
+            return Err(anyhow!("Presented swapchain images can't be read back; request a screenshot of the next frame instead."));
+        }
+        let source = match self.last_image {
+            Some(index) => self.data.swapchain_images[index],
+            None => return Err(anyhow!("No frame has been rendered yet.")),
+        };
+        // the image is only read once the frames using it are done
+        self.wait_idle();
+
+        return self.read_image(source);
+    }
+
+    /// Copies `source` into a host-visible linear image and returns it as
+    /// tightly packed RGBA8 rows. Work rendering to `source` must have been
+    /// submitted to the graphics queue before; the copy waits for it.
+    pub unsafe fn read_image(&mut self, source: vk::Image) -> Result<Vec<u8>> {
+
+        let bgra = match self.data.swapchain_format {
+            vk::Format::B8G8R8A8_SRGB | vk::Format::B8G8R8A8_UNORM => true,
+            vk::Format::R8G8B8A8_SRGB | vk::Format::R8G8B8A8_UNORM => false,
+            format => return Err(anyhow!("Screenshots of {:?} swapchains are not supported.", format)),
+        };
+        if !self.data.swapchain_usage.contains(vk::ImageUsageFlags::TRANSFER_SRC) {
+            return Err(anyhow!("The swapchain images can't be copied from on this surface."));
+        }
+
+        let extent = self.data.swapchain_extent;
+        let info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::_2D)
+        .format(self.data.swapchain_format)
+        .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(vk::SampleCountFlags::_1)
+        .tiling(vk::ImageTiling::LINEAR)
+        .usage(vk::ImageUsageFlags::TRANSFER_DST)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .initial_layout(vk::ImageLayout::UNDEFINED);
+
+        let image = self.device.create_image(&info, None)?;
+        track_created("Image");
+
+        let requirements = self.device.get_image_memory_requirements(image);
+        let result = match self.data.allocator.allocate(&self.device, "screenshot image", requirements, MemoryLocation::GpuToCpu) {
+            Ok(allocation) => {
+                let result = self.record_screenshot_copy(source, image, allocation)
+                .map(|()| self.read_screenshot_pixels(image, allocation, bgra));
+                self.data.allocator.free(allocation);
+                result
+            }
+            Err(error) => Err(error),
+        };
+
+        self.device.destroy_image(image, None);
+        track_destroyed("Image");
+
+        return result;
+    }
+
+    /// Binds `allocation` to `image` and copies `source` into it.
+    pub unsafe fn record_screenshot_copy(&self, source: vk::Image, image: vk::Image, allocation: Allocation) -> Result<()> {
+
+        self.device.bind_image_memory(image, allocation.memory, allocation.offset)?;
+
+        let mut recorder = CommandRecorder::graphics(&self.data).begin(&self.device)?;
+
+        let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+
+        let barrier = |image, old_layout, new_layout, src_access_mask, dst_access_mask| {
+            return vk::ImageMemoryBarrier::builder()
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(subresource_range)
+            .src_access_mask(src_access_mask)
+            .dst_access_mask(dst_access_mask);
+        };
+
+        let to_transfer = [
+            barrier(image, vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::AccessFlags::empty(), vk::AccessFlags::TRANSFER_WRITE),
+            barrier(source, final_layout(&self.data), vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::AccessFlags::COLOR_ATTACHMENT_WRITE, vk::AccessFlags::TRANSFER_READ),
+        ];
+        // after the render pass submitted just before on the same queue
+        self.device.cmd_pipeline_barrier(
+            recorder.record(),
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &to_transfer,
+        );
+
+        let subresource = vk::ImageSubresourceLayers::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(0)
+        .base_array_layer(0)
+        .layer_count(1);
+
+        let extent = self.data.swapchain_extent;
+        let region = vk::ImageCopy::builder()
+        .src_subresource(subresource)
+        .dst_subresource(subresource)
+        .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 });
+
+        self.device.cmd_copy_image(
+            recorder.record(),
+            source,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[region],
+        );
+
+        let from_transfer = [
+            barrier(image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::GENERAL, vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::HOST_READ),
+            barrier(source, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, final_layout(&self.data), vk::AccessFlags::TRANSFER_READ, vk::AccessFlags::MEMORY_READ),
+        ];
+        self.device.cmd_pipeline_barrier(
+            recorder.record(),
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::HOST | vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &from_transfer,
+        );
+
+        return recorder.flush();
+    }
+
+    pub unsafe fn read_screenshot_pixels(&self, image: vk::Image, allocation: Allocation, bgra: bool) -> Vec<u8> {
+
+        let subresource = vk::ImageSubresource::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(0)
+        .array_layer(0);
+        let layout = self.device.get_image_subresource_layout(image, &subresource);
+
+        let extent = self.data.swapchain_extent;
+        let mapped = std::slice::from_raw_parts(
+            (allocation.mapped as *const u8).add(layout.offset as usize),
+            (layout.row_pitch * extent.height as u64) as usize,
+        );
+        return pack_rgba_rows(mapped, extent.width, extent.height, layout.row_pitch as usize, bgra);
+    }
+
+    /// Waits for the device to finish all submitted work. Every teardown and
+    /// recreation path goes through here so none of them forget to wait.
+    pub unsafe fn wait_idle(&self) {
+        if let Err(error) = self.device.device_wait_idle() {
+            error!("Failed to wait for device idle: {}", error);
+        }
+    }
+
+    /// Destroys the swapchain and the objects sized to it (image views,
+    /// framebuffers, command buffers), resetting the handles so they can be
+    /// created again. The render pass and pipeline are kept.
+    pub unsafe fn destroy_swapchain_objects(&mut self) {
+
+        if !self.data.command_buffers.is_empty() {
+            self.device.free_command_buffers(self.data.command_pool, &self.data.command_buffers);
+            self.data.command_buffers.clear();
+        }
+        self.data.images_in_flight.clear();
+
+        destroy_render_target(&self.device, &mut self.data);
+        destroy_uniform_objects(&self.device, &mut self.data);
+
+        self.data.framebuffers
+        .drain(..)
+        .for_each(|f| {
+            self.device.destroy_framebuffer(f, None);
+            track_destroyed("Framebuffer");
+        });
+
+        if !self.data.query_pool.is_null() {
+            self.device.destroy_query_pool(self.data.query_pool, None);
+            self.data.query_pool = vk::QueryPool::null();
+            track_destroyed("QueryPool");
+        }
+
+        if !self.data.color_image_view.is_null() {
+            self.device.destroy_image_view(self.data.color_image_view, None);
+            self.data.color_image_view = vk::ImageView::null();
+            track_destroyed("ImageView");
+        }
+        if let Some(allocation) = self.data.color_image_allocation.take() {
+            self.device.destroy_image(self.data.color_image, None);
+            self.data.color_image = vk::Image::null();
+            track_destroyed("Image");
+            self.data.allocator.free(allocation);
+        }
+
+        self.data.swapchain_image_views
+        .drain(..)
+        .for_each(|v| {
+            self.device.destroy_image_view(v, None);
+            track_destroyed("ImageView");
+        });
+
+        if !self.data.swapchain.is_null() {
+            self.device.destroy_swapchain_khr(self.data.swapchain, None);
+            self.data.swapchain = vk::SwapchainKHR::null();
+            track_destroyed("SwapchainKHR");
+        }
+        // a headless app owns its images
+        if let Some(allocation) = self.data.offscreen_allocation.take() {
+            self.data.swapchain_images.iter().for_each(|&i| {
+                self.device.destroy_image(i, None);
+                track_destroyed("Image");
+            });
+            self.data.allocator.free(allocation);
+        }
+        self.data.swapchain_images.clear();
+
+    }
+
+    /// Destroys the render pass and the pipeline built for it, which outlive
+    /// swapchain recreation unless the swapchain format changes.
+    pub unsafe fn destroy_pipeline_objects(&mut self) {
+
+        self.destroy_pipelines();
+
+        if !self.data.render_pass.is_null() {
+            self.device.destroy_render_pass(self.data.render_pass, None);
+            self.data.render_pass = vk::RenderPass::null();
+            track_destroyed("RenderPass");
+        }
+
+    }
+
+    /// Destroys the graphics pipelines and their shared layout.
+    pub unsafe fn destroy_pipelines(&mut self) {
+
+        for pipeline in [&mut self.data.pipeline, &mut self.data.wireframe_pipeline] {
+            if !pipeline.is_null() {
+                self.device.destroy_pipeline(*pipeline, None);
+                *pipeline = vk::Pipeline::null();
+                track_destroyed("Pipeline");
+            }
+        }
+
+        if !self.data.pipeline_layout.is_null() {
+            self.device.destroy_pipeline_layout(self.data.pipeline_layout, None);
+            self.data.pipeline_layout = vk::PipelineLayout::null();
+            track_destroyed("PipelineLayout");
+        }
+
+    }
+
+    /// Tears everything down. Also used on a partially created app, so handles
+    /// that were never created (still null) are skipped.
+    pub unsafe fn destroy(&mut self) {
+        self.destroy_device_objects(true);
+        destroy_instance_objects(&self.instance, &mut self.data);
+    }
+
+    /// Destroys the logical device and everything created from it, resetting
+    /// the handles so `create_device_objects` can run again. The pipeline cache
+    /// is only saved when `save_cache` is set; after a device loss its
+    /// contents can't be trusted.
+    pub unsafe fn destroy_device_objects(&mut self, save_cache: bool) {
+        if self.device_destroyed {
+            return;
+        }
+        self.wait_idle();
+
+        self.destroy_swapchain_objects();
+        self.destroy_pipeline_objects();
+
+        destroy_model_buffers(&self.device, &mut self.data);
+
+        if !self.data.descriptor_set_layout.is_null() {
+            self.device.destroy_descriptor_set_layout(self.data.descriptor_set_layout, None);
+            self.data.descriptor_set_layout = vk::DescriptorSetLayout::null();
+            track_destroyed("DescriptorSetLayout");
+        }
+
+        if !self.data.pipeline_cache.is_null() {
+            if save_cache {
+                if let Err(error) = save_pipeline_cache(&self.device, &self.data) {
+                    warn!("Failed to save pipeline cache: {}", error);
+                }
+            }
+            self.device.destroy_pipeline_cache(self.data.pipeline_cache, None);
+            self.data.pipeline_cache = vk::PipelineCache::null();
+            track_destroyed("PipelineCache");
+        }
+
+        self.data.in_flight_fences
+        .drain(..)
+        .for_each(|f| {
+            self.device.destroy_fence(f, None);
+            track_destroyed("Fence");
+        });
+
+        self.data.image_available_semaphores
+        .drain(..)
+        .chain(self.data.render_finished_semaphores.drain(..))
+        .for_each(|s| {
+            self.device.destroy_semaphore(s, None);
+            track_destroyed("Semaphore");
+        });
+
+        for pool in [&mut self.data.command_pool, &mut self.data.transfer_command_pool, &mut self.data.compute_command_pool] {
+            if !pool.is_null() {
+                self.device.destroy_command_pool(*pool, None);
+                *pool = vk::CommandPool::null();
+                track_destroyed("CommandPool");
+            }
+        }
+
+        self.data.allocator.destroy(&self.device);
+
+        self.device.destroy_device(None);
+        self.device_destroyed = true;
+        track_destroyed("Device");
+    }
+}
+
+impl App {
+    pub fn save_screenshot(&mut self) {
+        if let Some(app) = &mut self.app {
+            let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+            let path = PathBuf::from(format!("screenshot-{}.png", timestamp));
+
+            // saved when the next frame renders
+            app.request_screenshot(path);
+            self.needs_redraw = true;
+        }
+    }
+
+    /// Grabs and hides the cursor for mouse look, or releases it.
+    pub fn toggle_cursor_capture(&mut self) {
+        if let Some(window) = &self.window {
+            let captured = !self.cursor_captured;
+            let result = match captured {
+                // not every platform can lock the cursor in place
+                true => window
+                .set_cursor_grab(CursorGrabMode::Locked)
+                .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined)),
+                false => window.set_cursor_grab(CursorGrabMode::None),
+            };
+            match result {
+                Ok(()) => {
+                    window.set_cursor_visible(!captured);
+                    self.cursor_captured = captured;
+                }
+                Err(error) => warn!("Failed to {} the cursor: {}", if captured { "capture" } else { "release" }, error),
+            }
+        }
+        self.camera_controls.look = (0.0, 0.0);
+    }
+
+    /// Acts on the keyboard once per event loop iteration: held keys move the
+    /// camera, and keys pressed since the last call trigger their toggles.
+    pub fn handle_input(&mut self, event_loop: &ActiveEventLoop) {
+        self.camera_controls.hold(&self.input.held);
+        // keep moving smoothly in idle mode rather than on key repeats
+        if self.camera_controls.movement() != Vector3::zero() {
+            self.needs_redraw = true;
+        }
+
+        if self.input.just_pressed(KeyCode::Escape) {
+            event_loop.exit();
+        }
+        if self.input.just_pressed(KeyCode::Tab) {
+            self.toggle_cursor_capture();
+        }
+        if self.input.just_pressed(KeyCode::F11) {
+            self.toggle_fullscreen();
+        }
+        if self.input.just_pressed(KeyCode::PrintScreen) {
+            self.save_screenshot();
+        }
+        if let Some(app) = &mut self.app {
+            if self.input.just_pressed(KeyCode::F3) {
+                let wireframe = !app.data.wireframe;
+                if let Err(error) = unsafe { app.set_wireframe(wireframe) } {
+                    error!("Failed to switch polygon mode: {}", error);
+                }
+            }
+            if self.input.just_pressed(KeyCode::KeyV) {
+                if let Err(error) = unsafe { app.toggle_vsync() } {
+                    error!("Failed to toggle vsync: {}", error);
+                }
+            }
+        }
+
+        self.input.end_frame();
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        if let Some(window) = &self.window {
+            self.fullscreen = !self.fullscreen;
+            window.set_fullscreen(self.fullscreen.then_some(Fullscreen::Borderless(None)));
+        }
+        // the surface size changes; don't rely on every platform sending
+        // Resized before the next frame
+        if let Some(app) = &mut self.app {
+            app.resized = true;
+        }
+    }
+}
+
+impl ApplicationHandler for App {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        let mut window_props = Window::default_attributes()
+        .with_title(&self.config.title)
+        .with_inner_size(LogicalSize::new(self.config.width, self.config.height));
+
+        if let Some(geometry) = WindowGeometry::load().filter(|_| !self.reset_window) {
+            let monitors = event_loop
+            .available_monitors()
+            .map(|m| (m.position(), m.size()))
+            .collect::<Vec<_>>();
+            let geometry = geometry.clamp_to_monitors(&monitors);
+
+            window_props = window_props.with_position(PhysicalPosition::new(geometry.x, geometry.y));
+            // a size asked for on the command line or environment wins
+            if !self.config.explicit_size {
+                window_props = window_props.with_inner_size(PhysicalSize::new(geometry.width, geometry.height));
+            }
+        }
+
+        let result = event_loop
+        .create_window(window_props)
+        .context("Failed to create the window")
+        .and_then(|window| {
+            let app = VulkanApp::create(Some(&window), &self.config, RequiredFeatures::default()).context("Failed to initialize Vulkan")?;
+            return Ok((window, app));
+        });
+
+        match result {
+            Ok((window, app)) => {
+                self.scale_factor = window.scale_factor();
+                self.needs_redraw = true;
+                self.window = Some(window);
+                self.app = Some(app);
+            }
+            Err(error) => {
+                error!("{:#}", error);
+                self.init_error = Some(error);
+                event_loop.exit();
+            }
+        }
+    }
+
+    fn window_event(
+            &mut self,
+            event_loop: &ActiveEventLoop,
+            window_id: window::WindowId,
+            event: WindowEvent,
+        ) {
+        match event {
+            WindowEvent::KeyboardInput { device_id, event, is_synthetic } => {
+                self.needs_redraw = true;
+                // acted on in `handle_input`
+                if let PhysicalKey::Code(code) = event.physical_key {
+                    self.input.key(code, event.state == ElementState::Pressed);
+                }
+            }
+
+            WindowEvent::Focused(false) => {
+                // key releases go to whichever window has focus now
+                self.input.clear();
+            }
+
+            WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
+                info!("Window scale factor changed from {} to {}.", self.scale_factor, scale_factor);
+                self.scale_factor = scale_factor;
+                self.needs_redraw = true;
+                if let Some(app) = &mut self.app {
+                    app.resized = true;
+                }
+            }
+
+            WindowEvent::Resized(_) => {
+                self.needs_redraw = true;
+                if let Some(app) = &mut self.app {
+                    app.resized = true;
+                }
+            }
+
+            WindowEvent::RedrawRequested => {
+                if let (Some(window), Some(app)) = (&self.window, &mut self.app) {
+                    app.update_camera(&mut self.camera_controls);
+                    if let Err(error) = unsafe { app.render(window) } {
+                        error!("Failed to render frame: {}", error);
+                        event_loop.exit();
+                    }
+                }
+            }
+
+            WindowEvent::CloseRequested => {
+                event_loop.exit();
+            }
+
+            _ => ()
+        }
+    }
+
+    fn device_event(&mut self, event_loop: &ActiveEventLoop, device_id: event::DeviceId, event: DeviceEvent) {
+        // raw motion keeps coming while the cursor is locked in place
+        if let (DeviceEvent::MouseMotion { delta }, true) = (event, self.cursor_captured) {
+            self.camera_controls.look.0 += delta.0;
+            self.camera_controls.look.1 += delta.1;
+            self.needs_redraw = true;
+        }
+    }
+
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        // `exiting` does the teardown; skip it if the window is already closing
+        if interrupted() && !event_loop.exiting() {
+            info!("Interrupted, shutting down.");
+            event_loop.exit();
+        }
+        if event_loop.exiting() {
+            return;
+        }
+
+        self.handle_input(event_loop);
+
+        if let (Some(watch), Some(app)) = (&mut self.shader_watch, &mut self.app) {
+            if watch.changed() {
+                match unsafe { app.reload_shaders() } {
+                    Ok(()) => info!("Reloaded shaders."),
+                    Err(error) => error!("Failed to reload shaders, keeping the previous pipeline: {:#}", error),
+                }
+                self.needs_redraw = true;
+            }
+        }
+
+        // request redraw when other events have passed
+        if self.idle {
+            // keep polling the shader files and for interrupts while idle;
+            // a signal doesn't wake the event loop
+            event_loop.set_control_flow(ControlFlow::WaitUntil(Instant::now() + Duration::from_millis(500)));
+            if self.needs_redraw {
+                self.needs_redraw = false;
+                if let Some(window) = &self.window {
+                    if let Some(limiter) = &mut self.frame_limiter {
+                        limiter.wait();
+                    }
+                    window.request_redraw();
+                }
+            }
+        } else if let Some(window) = &self.window {
+            // the previous frame has rendered by now
+            if let Some(limiter) = &mut self.frame_limiter {
+                limiter.wait();
+            }
+            window.request_redraw();
+        }
+    }
+
+    fn exiting(&mut self, event_loop: &ActiveEventLoop) {
+        if let Some(window) = &self.window {
+            // not every platform can report the window position (e.g. Wayland);
+            // a fullscreen window would save the monitor size instead of the
+            // windowed geometry, so keep the previous one
+            if let (Ok(position), false) = (window.outer_position(), self.fullscreen) {
+                let size = window.inner_size();
+                let geometry = WindowGeometry { x: position.x, y: position.y, width: size.width, height: size.height };
+                if let Err(error) = geometry.save() {
+                    warn!("Failed to save window geometry: {}", error);
+                }
+            }
+        }
+
+        // taken so nothing can reach the destroyed app afterwards
+        if let Some(mut app) = self.app.take() {
+            unsafe {
+                app.destroy();
+            }
+        }
+    }
+
+}
+
+/// Window placement saved on exit and restored on the next launch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32
+}
+
+impl WindowGeometry {
+    pub fn path() -> Result<PathBuf> {
+        return Ok(std::env::current_exe()?.with_file_name("window_geometry.txt"));
+    }
+
+    pub fn load() -> Option<Self> {
+        let text = std::fs::read_to_string(Self::path().ok()?).ok()?;
+        return Self::parse(&text);
+    }
+
+    pub fn save(&self) -> Result<()> {
+        std::fs::write(Self::path()?, format!("{} {} {} {}\n", self.x, self.y, self.width, self.height))?;
+        return Ok(());
+    }
+
+    pub fn parse(_text: &str) -> Option<Self> {
+        let mut fields = _text.split_whitespace();
+        let geometry = Self {
+            x: fields.next()?.parse().ok()?,
+            y: fields.next()?.parse().ok()?,
+            width: fields.next()?.parse().ok()?,
+            height: fields.next()?.parse().ok()?
+        };
+
+        if geometry.width == 0 || geometry.height == 0 {
+            return None;
+        }
+
+        return Some(geometry);
+    }
+
+    /// Keeps the window reachable when the monitor it was saved on is gone by
+    /// moving it onto the first available monitor.
+    pub fn clamp_to_monitors(self, _monitors: &[(PhysicalPosition<i32>, PhysicalSize<u32>)]) -> Self {
+        let visible = _monitors.iter().any(|(position, size)| {
+            self.x >= position.x && self.x < position.x + size.width as i32
+            && self.y >= position.y && self.y < position.y + size.height as i32
+        });
+
+        if visible {
+            return self;
+        }
+
+        if let Some((position, size)) = _monitors.first() {
+            let max_x = position.x + (size.width.saturating_sub(self.width)) as i32;
+            let max_y = position.y + (size.height.saturating_sub(self.height)) as i32;
+            return Self {
+                x: self.x.clamp(position.x, max_x),
+                y: self.y.clamp(position.y, max_y),
+                ..self
+            };
+        }
+
+        return self;
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AppData {
+    pub messenger: vk::DebugUtilsMessengerEXT,
+    // fallback for loaders without VK_EXT_debug_utils
+    pub debug_report_callback: vk::DebugReportCallbackEXT,
+    pub surface: vk::SurfaceKHR,
+    pub physical_device: vk::PhysicalDevice,
+    pub graphics_queue: vk::Queue,
+    pub present_queue: vk::Queue,
+    pub transfer_queue: vk::Queue,
+    // null when the device has no compute-capable family
+    pub compute_queue: vk::Queue,
+    pub swapchain: vk::SwapchainKHR,
+    pub swapchain_format: vk::Format,
+    pub swapchain_extent: vk::Extent2D,
+    pub swapchain_usage: vk::ImageUsageFlags,
+    // what besides rendering uses the swapchain images, deciding their usage
+    pub swapchain_access: SwapchainAccess,
+    pub swapchain_images: Vec<vk::Image>,
+    pub swapchain_image_views: Vec<vk::ImageView>,
+    // MSAA samples asked for, from `AppConfig`, and the count in effect
+    pub msaa_request: u32,
+    pub msaa_samples: vk::SampleCountFlags,
+    // multisampled image resolved into the swapchain image; null without MSAA
+    pub color_image: vk::Image,
+    pub color_image_allocation: Option<Allocation>,
+    pub color_image_view: vk::ImageView,
+    pub render_pass: vk::RenderPass,
+    // one uniform buffer binding at the vertex stage
+    pub descriptor_set_layout: vk::DescriptorSetLayout,
+    // per swapchain image, like the command buffers that bind them
+    pub descriptor_pool: vk::DescriptorPool,
+    pub descriptor_sets: Vec<vk::DescriptorSet>,
+    pub uniform_buffers: Vec<vk::Buffer>,
+    // persistently mapped memory of `uniform_buffers`
+    pub uniform_allocations: Vec<Allocation>,
+    // OBJ file to draw, from `AppConfig`; the built-in triangle without one
+    pub model_path: Option<PathBuf>,
+    pub vertex_buffer: vk::Buffer,
+    pub vertex_allocation: Option<Allocation>,
+    pub index_buffer: vk::Buffer,
+    pub index_allocation: Option<Allocation>,
+    pub index_count: u32,
+    // model matrix of each object drawn with the model, pushed as a push
+    // constant before its draw; re-record the command buffers after changes
+    pub object_transforms: Vec<Matrix4<f32>>,
+    pub pipeline_layout: vk::PipelineLayout,
+    pub pipeline: vk::Pipeline,
+    // same pipeline with LINE polygon mode; null without fillModeNonSolid
+    pub wireframe_pipeline: vk::Pipeline,
+    // draw with `wireframe_pipeline`, toggled with F3
+    pub wireframe: bool,
+    // shader functions the graphics pipeline starts in
+    pub entry_points: EntryPoints,
+    // persisted across runs in pipeline_cache.bin
+    pub pipeline_cache: vk::PipelineCache,
+    // every buffer and image gets its memory from here
+    pub allocator: MemoryAllocator,
+    pub framebuffers: Vec<vk::Framebuffer>,
+    pub command_pool: vk::CommandPool,
+    // transient pool on the transfer family for one-shot copies
+    pub transfer_command_pool: vk::CommandPool,
+    // transient pool on the compute family for `dispatch`, if there is one
+    pub compute_command_pool: vk::CommandPool,
+    // distinct graphics, transfer and compute families; buffers are shared
+    // concurrently between them when there are several
+    pub buffer_queue_families: Vec<u32>,
+    pub command_buffers: Vec<vk::CommandBuffer>,
+    // the clear color each command buffer was recorded with; a buffer is
+    // re-recorded before use once it differs from `clear_color`
+    pub recorded_clear_colors: Vec<[f32; 4]>,
+    // what each command buffer was last recorded with, counted per submission
+    pub recorded_stats: Vec<DrawStats>,
+    pub clear_color: [f32; 4],
+    // cycle `clear_color` over time, replacing whatever it was set to
+    // (`AppConfig::animate_clear`)
+    pub animate_clear: bool,
+    // two timestamps (render pass start and end) per swapchain image; null
+    // when the device can't time graphics work
+    pub query_pool: vk::QueryPool,
+    // nanoseconds per timestamp tick
+    pub timestamp_period: f32,
+    // low bits of each timestamp that hold the count, from the graphics
+    // queue family
+    pub timestamp_valid_bits: u32,
+    // per frame in flight
+    pub image_available_semaphores: Vec<vk::Semaphore>,
+    pub render_finished_semaphores: Vec<vk::Semaphore>,
+    pub in_flight_fences: Vec<vk::Fence>,
+    // per swapchain image: the fence of the frame last rendered to it, if any
+    pub images_in_flight: Vec<vk::Fence>,
+    // names of what was actually enabled, kept for logging and inspection
+    pub instance_layers: Vec<String>,
+    pub instance_extensions: Vec<String>,
+    pub device_extensions: Vec<String>,
+    // core features enabled on the device, for checking shader capabilities
+    pub enabled_features: vk::PhysicalDeviceFeatures,
+    // shaderFloat16/shaderInt8 as enabled; all false without
+    // VK_KHR_shader_float16_int8
+    pub float16_int8_features: vk::PhysicalDeviceShaderFloat16Int8Features,
+    // maxSamplerAnisotropy when samplerAnisotropy is enabled, otherwise None
+    pub max_sampler_anisotropy: Option<f32>,
+    // API version the instance was created with, negotiated with the loader;
+    // gate features from newer versions on it (None before the instance exists)
+    pub api_version: Option<Version>,
+    // whether the instance was created with portability enumeration
+    pub portability: bool,
+    // VULCAN_PORTABILITY override, read when the instance is created
+    pub portability_mode: PortabilityMode,
+    // validation layers and debug messenger, from `AppConfig`
+    pub validation: bool,
+    // requested present mode; the swapchain falls back to FIFO without it
+    pub present_mode: PresentMode,
+    // features devices must have, from `VulkanApp::create`
+    pub required_features: RequiredFeatures,
+    // index into the logged device list to use instead of the scored choice
+    pub forced_gpu: Option<usize>,
+    // attempts at recreating a lost device, from `AppConfig`
+    pub device_recoveries: u32,
+    // queue global priority: the requested one until the device exists, then
+    // the one actually in effect (None if the default is used)
+    pub queue_priority: Option<vk::QueueGlobalPriorityKHR>,
+    // features enabled through VK_KHR_portability_subset, if that path is active
+    pub portability_subset: Option<vk::PhysicalDevicePortabilitySubsetFeaturesKHR>,
+    // size of the offscreen target when running without a window or surface
+    pub headless: Option<vk::Extent2D>,
+    // memory of the offscreen image standing in for the swapchain when headless
+    pub offscreen_allocation: Option<Allocation>,
+    // render-to-texture target sampled by a later pass; its handles live as
+    // long as the swapchain objects
+    pub render_target: Option<OffscreenTarget>
+}
+
+/// Whether `_error` is a `VK_ERROR_DEVICE_LOST` result, which only a new
+/// device recovers from.
+pub fn is_device_lost(_error: &anyhow::Error) -> bool {
+    return _error.downcast_ref::<vk::ErrorCode>() == Some(&vk::ErrorCode::DEVICE_LOST);
+}
+
+/// Packs mapped image rows (`_row_pitch` bytes apart) into tight RGBA8,
+/// swapping the red and blue channels of BGRA data.
+pub fn pack_rgba_rows(_mapped: &[u8], _width: u32, _height: u32, _row_pitch: usize, _bgra: bool) -> Vec<u8> {
+
+    let row_bytes = _width as usize * 4;
+    let mut pixels = Vec::with_capacity(row_bytes * _height as usize);
+    for row in 0.._height as usize {
+        let start = row * _row_pitch;
+        pixels.extend_from_slice(&_mapped[start..start + row_bytes]);
+    }
+
+    if _bgra {
+        pixels.chunks_exact_mut(4).for_each(|p| p.swap(0, 2));
+    }
+
+    return pixels;
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_geometry_parses_saved_text() {
+        let geometry = WindowGeometry::parse("10 -20 800 600\n").unwrap();
+        assert_eq!(geometry, WindowGeometry { x: 10, y: -20, width: 800, height: 600 });
+        assert!(WindowGeometry::parse("10 20 0 600").is_none());
+        assert!(WindowGeometry::parse("garbage").is_none());
+    }
+
+    #[test]
+    fn window_geometry_is_moved_back_on_screen() {
+        let monitors = [(PhysicalPosition::new(0, 0), PhysicalSize::new(1920, 1080))];
+
+        let on_screen = WindowGeometry { x: 100, y: 100, width: 800, height: 600 };
+        assert_eq!(on_screen.clamp_to_monitors(&monitors), on_screen);
+
+        let off_screen = WindowGeometry { x: 5000, y: -900, width: 800, height: 600 };
+        let clamped = off_screen.clamp_to_monitors(&monitors);
+        assert_eq!((clamped.x, clamped.y), (1120, 0));
+    }
+
+    #[test]
+    fn only_device_lost_triggers_recovery() {
+        assert!(is_device_lost(&anyhow!(vk::ErrorCode::DEVICE_LOST)));
+        assert!(is_device_lost(&anyhow!(vk::ErrorCode::DEVICE_LOST).context("Failed to render frame")));
+        assert!(!is_device_lost(&anyhow!(vk::ErrorCode::OUT_OF_DATE_KHR)));
+        assert!(!is_device_lost(&anyhow!("device lost")));
+    }
+
+    #[test]
+    fn frame_limiter_keeps_pace_without_bursts() {
+        let frame = Duration::from_millis(10);
+        let start = Instant::now();
+        assert_eq!(FrameLimiter::next_deadline(start, frame, start + Duration::from_millis(4)), start + frame);
+        // a 25ms frame doesn't get the 10..20ms slot back
+        let late = start + Duration::from_millis(25);
+        assert_eq!(FrameLimiter::next_deadline(start, frame, late), late + frame);
+
+        let mut limiter = FrameLimiter::new(200);
+        let before = Instant::now();
+        limiter.wait();
+        limiter.wait();
+        assert!(before.elapsed() >= Duration::from_millis(5));
+    }
+
+    #[test]
+    fn screenshot_rows_drop_padding_and_swizzle() {
+        // 1x2 image with 8-byte rows: one BGRA pixel plus 4 bytes of padding each
+        let mapped = [1, 2, 3, 4, 0, 0, 0, 0, 5, 6, 7, 8, 0, 0, 0, 0];
+        assert_eq!(pack_rgba_rows(&mapped, 1, 2, 8, true), vec![3, 2, 1, 4, 7, 6, 5, 8]);
+        assert_eq!(pack_rgba_rows(&mapped, 1, 2, 8, false), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn headless_frame_reads_back_as_rgba() {
+        let config = AppConfig {
+            validation: false,
+            headless: Some(vk::Extent2D { width: 64, height: 32 }),
+            ..Default::default()
+        };
+        // no loader, no device or none suitable
+        let mut app = match VulkanApp::create(None, &config, RequiredFeatures::default()) {
+            Ok(app) => app,
+            Err(error) => {
+                eprintln!("No usable Vulkan device ({:#}), skipping.", error);
+                return;
+            }
+        };
+        let pixels = unsafe { app.render_headless().and_then(|()| app.read_frame()) };
+        unsafe {
+            app.destroy();
+        }
+
+        let pixels = pixels.unwrap();
+        assert_eq!(pixels.len(), 64 * 32 * 4);
+        // the triangle doesn't reach the corners, which keep the gray clear color
+        assert!(pixels[0] > 0 && pixels[0] == pixels[1] && pixels[1] == pixels[2]);
+        assert_eq!(pixels[3], 255);
+    }
+}
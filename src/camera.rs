@@ -0,0 +1,200 @@
+//! Free-fly camera driven by WASD movement and mouse look.
+
+use cgmath::{perspective, Deg, InnerSpace, Matrix4, Point3, Vector3, Zero};
+
+use winit::keyboard::KeyCode;
+
+use std::collections::HashSet;
+
+// world units per second
+pub const CAMERA_SPEED: f32 = 2.0;
+// degrees of yaw/pitch per pixel of mouse movement
+pub const MOUSE_SENSITIVITY: f32 = 0.1;
+// looking straight up or down makes the view matrix degenerate
+pub const MAX_PITCH: f32 = 89.0;
+pub const NEAR_PLANE: f32 = 0.1;
+pub const FAR_PLANE: f32 = 100.0;
+// longest time step one camera update moves for, so a key pressed after a
+// long gap between redraws (e.g. with --idle) doesn't jump the camera
+pub const MAX_CAMERA_STEP: f32 = 0.1;
+
+/// Maps cgmath's OpenGL clip space (y up, depth -1..1) to Vulkan's (y down,
+/// depth 0..1).
+pub const VULKAN_CLIP: Matrix4<f32> = Matrix4::new(
+    1.0,  0.0, 0.0, 0.0,
+    0.0, -1.0, 0.0, 0.0,
+    0.0,  0.0, 0.5, 0.0,
+    0.0,  0.0, 0.5, 1.0,
+);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera {
+    pub position: Point3<f32>,
+    // rotation around the world y axis; -90 looks down -z
+    pub yaw: Deg<f32>,
+    pub pitch: Deg<f32>,
+    // vertical field of view
+    pub fov: Deg<f32>
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        // backed off from the origin, where the triangle sits
+        return Self {
+            position: Point3::new(0.0, 0.0, 2.0),
+            yaw: Deg(-90.0),
+            pitch: Deg(0.0),
+            fov: Deg(45.0)
+        };
+    }
+}
+
+impl Camera {
+    pub fn forward(&self) -> Vector3<f32> {
+        let (yaw, pitch) = (cgmath::Rad::from(self.yaw).0, cgmath::Rad::from(self.pitch).0);
+        return Vector3::new(pitch.cos() * yaw.cos(), pitch.sin(), pitch.cos() * yaw.sin()).normalize();
+    }
+
+    pub fn right(&self) -> Vector3<f32> {
+        return self.forward().cross(Vector3::unit_y()).normalize();
+    }
+
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        return Matrix4::look_to_rh(self.position, self.forward(), Vector3::unit_y());
+    }
+
+    /// Projection for a viewport of the given width/height ratio, in Vulkan
+    /// clip space.
+    pub fn projection_matrix(&self, aspect: f32) -> Matrix4<f32> {
+        return VULKAN_CLIP * perspective(self.fov, aspect, NEAR_PLANE, FAR_PLANE);
+    }
+
+    /// Moves along `movement` (right, up, forward; each -1..1) for `dt` seconds
+    /// and turns by `look`, a mouse delta in pixels.
+    pub fn update(&mut self, movement: Vector3<f32>, look: (f32, f32), dt: f32) {
+        self.yaw += Deg(look.0 * MOUSE_SENSITIVITY);
+        self.pitch = Deg((self.pitch.0 - look.1 * MOUSE_SENSITIVITY).clamp(-MAX_PITCH, MAX_PITCH));
+
+        if movement != Vector3::zero() {
+            let direction = self.right() * movement.x + Vector3::unit_y() * movement.y + self.forward() * movement.z;
+            self.position += direction.normalize() * CAMERA_SPEED * dt;
+        }
+    }
+}
+
+/// Seconds to move the camera for, given the time since its last update.
+pub fn camera_step(_elapsed: std::time::Duration) -> f32 {
+    return _elapsed.as_secs_f32().min(MAX_CAMERA_STEP);
+}
+
+// handled by `CameraControls::key`
+pub const CAMERA_KEYS: [KeyCode; 6] = [KeyCode::KeyW, KeyCode::KeyS, KeyCode::KeyA, KeyCode::KeyD, KeyCode::Space, KeyCode::ShiftLeft];
+
+/// Held movement keys and the mouse movement since the camera last updated.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CameraControls {
+    pub forward: bool,
+    pub back: bool,
+    pub left: bool,
+    pub right: bool,
+    pub up: bool,
+    pub down: bool,
+    pub look: (f64, f64)
+}
+
+impl CameraControls {
+    /// Records a movement key press or release; returns false for other keys.
+    pub fn key(&mut self, code: KeyCode, pressed: bool) -> bool {
+        let held = match code {
+            KeyCode::KeyW => &mut self.forward,
+            KeyCode::KeyS => &mut self.back,
+            KeyCode::KeyA => &mut self.left,
+            KeyCode::KeyD => &mut self.right,
+            KeyCode::Space => &mut self.up,
+            KeyCode::ShiftLeft => &mut self.down,
+            _ => return false,
+        };
+        *held = pressed;
+        return true;
+    }
+
+    /// Sets every movement key to whether it's in `held`.
+    pub fn hold(&mut self, held: &HashSet<KeyCode>) {
+        for code in CAMERA_KEYS {
+            self.key(code, held.contains(&code));
+        }
+    }
+
+    pub fn movement(&self) -> Vector3<f32> {
+        let axis = |positive: bool, negative: bool| positive as i32 as f32 - negative as i32 as f32;
+        return Vector3::new(axis(self.right, self.left), axis(self.up, self.down), axis(self.forward, self.back));
+    }
+
+    /// Returns the accumulated mouse movement and starts over.
+    pub fn take_look(&mut self) -> (f32, f32) {
+        let (x, y) = std::mem::take(&mut self.look);
+        return (x as f32, y as f32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::{Transform, Vector4};
+
+    #[test]
+    fn camera_projects_what_it_faces_into_vulkan_clip_space() {
+        let camera = Camera::default();
+        let clip_space = camera.projection_matrix(16.0 / 9.0) * camera.view_matrix();
+
+        // a point straight ahead lands in the middle at a depth between 0 and 1
+        let ahead = clip_space * Vector4::new(0.0, 0.0, 0.0, 1.0);
+        let ndc = ahead.truncate() / ahead.w;
+        assert!(ndc.x.abs() < 1e-5 && ndc.y.abs() < 1e-5);
+        assert!(ndc.z > 0.0 && ndc.z < 1.0);
+
+        // world up is screen up, which is -y in Vulkan
+        let above = clip_space.transform_point(Point3::new(0.0, 0.5, 0.0));
+        assert!(above.y < 0.0);
+    }
+
+    #[test]
+    fn camera_moves_relative_to_where_it_looks() {
+        let mut camera = Camera::default();
+        camera.update(Vector3::new(0.0, 0.0, 1.0), (0.0, 0.0), 0.5);
+        assert!((camera.position.z - (2.0 - CAMERA_SPEED * 0.5)).abs() < 1e-5);
+
+        // turning right by 90 degrees faces +x
+        camera.update(Vector3::zero(), (90.0 / MOUSE_SENSITIVITY, 0.0), 0.0);
+        assert!((camera.forward() - Vector3::unit_x()).magnitude() < 1e-5);
+
+        camera.update(Vector3::zero(), (0.0, -10_000.0), 0.0);
+        assert_eq!(camera.pitch, Deg(MAX_PITCH));
+    }
+
+    #[test]
+    fn camera_steps_are_capped() {
+        use std::time::Duration;
+        assert_eq!(camera_step(Duration::from_millis(16)), 0.016);
+        assert_eq!(camera_step(Duration::from_secs(30)), MAX_CAMERA_STEP);
+    }
+
+    #[test]
+    fn controls_combine_opposite_keys() {
+        let mut controls = CameraControls::default();
+        assert!(controls.key(KeyCode::KeyW, true));
+        assert!(controls.key(KeyCode::KeyD, true));
+        assert!(!controls.key(KeyCode::KeyQ, true));
+        assert_eq!(controls.movement(), Vector3::new(1.0, 0.0, 1.0));
+        controls.key(KeyCode::KeyS, true);
+        assert_eq!(controls.movement(), Vector3::new(1.0, 0.0, 0.0));
+
+        controls.hold(&HashSet::from([KeyCode::KeyA, KeyCode::KeyQ]));
+        assert_eq!(controls.movement(), Vector3::new(-1.0, 0.0, 0.0));
+
+        controls.look = (3.0, -4.0);
+        assert_eq!(controls.take_look(), (3.0, -4.0));
+        assert_eq!(controls.take_look(), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,75 @@
+//! Linear and sRGB color handling for what gets written to the framebuffer.
+//!
+//! Colors in the app (`AppData::clear_color`, vertex colors, shader output)
+//! are linear. With an `_SRGB` swapchain format the hardware encodes them to
+//! sRGB on write, so they display as intended. A `UNORM` format stores values
+//! as they are, and the display then reads them as sRGB-encoded, which makes
+//! linear colors look too dark. Clear colors are therefore encoded by hand for
+//! `UNORM` targets; shader output isn't, so vertex colors only look right on
+//! `_SRGB` formats (the swapchain prefers one).
+
+use vulkanalia::prelude::v1_0::*;
+
+/// The 8-bit `_SRGB` formats, which are the ones usable as color attachments.
+pub fn is_srgb_format(_format: vk::Format) -> bool {
+    return matches!(
+        _format,
+        vk::Format::R8_SRGB
+        | vk::Format::R8G8_SRGB
+        | vk::Format::R8G8B8_SRGB
+        | vk::Format::B8G8R8_SRGB
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::A8B8G8R8_SRGB_PACK32
+    );
+}
+
+/// The sRGB transfer function, for a channel in 0..1.
+pub fn linear_to_srgb(_linear: f32) -> f32 {
+    if _linear <= 0.003_130_8 {
+        return _linear * 12.92;
+    }
+    return 1.055 * _linear.powf(1.0 / 2.4) - 0.055;
+}
+
+pub fn srgb_to_linear(_encoded: f32) -> f32 {
+    if _encoded <= 0.040_45 {
+        return _encoded / 12.92;
+    }
+    return ((_encoded + 0.055) / 1.055).powf(2.4);
+}
+
+/// The clear value that shows `_linear` on a framebuffer of `_format`: as is
+/// for `_SRGB` formats, which encode on write, and sRGB-encoded otherwise.
+/// Alpha is never encoded.
+pub fn clear_color_value(_linear: [f32; 4], _format: vk::Format) -> vk::ClearColorValue {
+    if is_srgb_format(_format) {
+        return vk::ClearColorValue { float32: _linear };
+    }
+    let [r, g, b, a] = _linear;
+    return vk::ClearColorValue { float32: [linear_to_srgb(r), linear_to_srgb(g), linear_to_srgb(b), a] };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::commands::CLEAR_COLOR;
+
+    #[test]
+    fn clear_colors_are_encoded_only_without_srgb_formats() {
+        for value in [0.0, 0.002, 0.1, 0.5, 1.0] {
+            assert!((srgb_to_linear(linear_to_srgb(value)) - value).abs() < 1e-5);
+        }
+
+        let linear = [0.2, 0.5, 1.0, 0.5];
+        assert_eq!(unsafe { clear_color_value(linear, vk::Format::B8G8R8A8_SRGB).float32 }, linear);
+        let encoded = unsafe { clear_color_value(linear, vk::Format::B8G8R8A8_UNORM).float32 };
+        assert!(encoded[0] > linear[0] && (encoded[2] - 1.0).abs() < 1e-5 && encoded[3] == 0.5);
+
+        // the default gray still writes 0.1 to UNORM framebuffers
+        let default = unsafe { clear_color_value(CLEAR_COLOR, vk::Format::R8G8B8A8_UNORM).float32 };
+        assert!((default[0] - 0.1).abs() < 1e-4);
+        assert!(!is_srgb_format(vk::Format::R16G16B16A16_SFLOAT));
+    }
+}
@@ -0,0 +1,655 @@
+//! Command pools and buffers, framebuffers, queries and sync objects.
+
+use anyhow::{anyhow, Result};
+
+use log::*;
+
+use cgmath::Matrix4;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::app::AppData;
+use crate::device::QueueFamilyIndices;
+use crate::handles::{track_created, track_destroyed};
+use crate::pipeline::PUSH_CONSTANTS_SIZE;
+use crate::color::{clear_color_value, linear_to_srgb, srgb_to_linear};
+
+// frames the CPU may record ahead of the GPU
+pub const MAX_FRAMES_IN_FLIGHT: usize = 2;
+// background color the render pass clears to until `AppData::clear_color`
+// changes; linear, so this is the 0.1 gray once encoded for display
+pub const CLEAR_COLOR: [f32; 4] = [0.010_022_6, 0.010_022_6, 0.010_022_6, 1.0];
+
+/// Where one-time command buffers come from and go to. `begin` starts a batch
+/// that records any number of operations into a single command buffer, which
+/// `flush` submits with one fence wait.
+#[derive(Clone, Copy, Debug)]
+pub struct CommandRecorder {
+    pub pool: vk::CommandPool,
+    pub queue: vk::Queue
+}
+
+impl CommandRecorder {
+    /// Copies and uploads, on the dedicated transfer queue when there is one.
+    pub fn transfer(data: &AppData) -> Self {
+        return Self { pool: data.transfer_command_pool, queue: data.transfer_queue };
+    }
+
+    /// Anything that needs a graphics queue, such as blits.
+    pub fn graphics(data: &AppData) -> Self {
+        return Self { pool: data.command_pool, queue: data.graphics_queue };
+    }
+
+    pub fn compute(data: &AppData) -> Self {
+        return Self { pool: data.compute_command_pool, queue: data.compute_queue };
+    }
+
+    pub unsafe fn begin(&self, device: &Device) -> Result<RecorderHandle> {
+        let allocate_info = vk::CommandBufferAllocateInfo::builder()
+        .command_pool(self.pool)
+        .level(vk::CommandBufferLevel::PRIMARY)
+        .command_buffer_count(1);
+
+        // owned by the handle from here on, so an early return frees it
+        let handle = RecorderHandle {
+            device: device.clone(),
+            recorder: *self,
+            command_buffer: device.allocate_command_buffers(&allocate_info)?[0],
+            operations: 0
+        };
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        device.begin_command_buffer(handle.command_buffer, &begin_info)?;
+
+        return Ok(handle);
+    }
+}
+
+/// A batch of one-time operations being recorded. Dropping it without a
+/// `flush` (e.g. on an error part-way) frees the command buffer unsubmitted.
+pub struct RecorderHandle {
+    device: Device,
+    recorder: CommandRecorder,
+    command_buffer: vk::CommandBuffer,
+    // operations recorded so far; an empty batch isn't submitted
+    pub operations: usize
+}
+
+impl RecorderHandle {
+    /// The command buffer, for recording something without a helper here.
+    pub fn record(&mut self) -> vk::CommandBuffer {
+        self.operations += 1;
+        return self.command_buffer;
+    }
+
+    pub unsafe fn copy_buffer(&mut self, source: vk::Buffer, destination: vk::Buffer, size: u64) {
+        let region = vk::BufferCopy::builder().size(size);
+        let command_buffer = self.record();
+        self.device.cmd_copy_buffer(command_buffer, source, destination, &[region]);
+    }
+
+    pub unsafe fn image_barrier(
+        &mut self,
+        barrier: vk::ImageMemoryBarrier,
+        src_stage: vk::PipelineStageFlags,
+        dst_stage: vk::PipelineStageFlags,
+    ) {
+        let command_buffer = self.record();
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            src_stage,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[barrier],
+        );
+    }
+
+    /// Submits everything recorded and waits for it on a fence, leaving the
+    /// rest of the queue's work alone.
+    pub unsafe fn flush(self) -> Result<()> {
+        self.device.end_command_buffer(self.command_buffer)?;
+        if self.operations == 0 {
+            return Ok(());
+        }
+
+        let fence = self.device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+        track_created("Fence");
+
+        let command_buffers = &[self.command_buffer];
+        let submit_info = vk::SubmitInfo::builder()
+        .command_buffers(command_buffers);
+
+        let result = self.device.queue_submit(self.recorder.queue, &[submit_info], fence)
+        .and_then(|()| self.device.wait_for_fences(&[fence], true, u64::MAX));
+
+        self.device.destroy_fence(fence, None);
+        track_destroyed("Fence");
+
+        return Ok(result.map(|_| ())?);
+    }
+}
+
+impl Drop for RecorderHandle {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.free_command_buffers(self.recorder.pool, &[self.command_buffer]);
+        }
+    }
+}
+
+/// Runs `_pipeline` with `_descriptor_sets` bound over the given number of
+/// workgroups on the compute queue and waits for it to finish. The shader's
+/// writes are visible to the host afterwards.
+pub unsafe fn dispatch(
+    _device: &Device,
+    _data: &AppData,
+    _pipeline: vk::Pipeline,
+    _layout: vk::PipelineLayout,
+    _descriptor_sets: &[vk::DescriptorSet],
+    _group_x: u32,
+    _group_y: u32,
+    _group_z: u32,
+) -> Result<()> {
+
+    if _data.compute_queue.is_null() {
+        return Err(anyhow!("The device has no compute queue."));
+    }
+
+    let mut recorder = CommandRecorder::compute(_data).begin(_device)?;
+    let command_buffer = recorder.record();
+    _device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, _pipeline);
+    if !_descriptor_sets.is_empty() {
+        _device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, _layout, 0, _descriptor_sets, &[]);
+    }
+    _device.cmd_dispatch(command_buffer, _group_x, _group_y, _group_z);
+
+    let barrier = vk::MemoryBarrier::builder()
+    .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+    .dst_access_mask(vk::AccessFlags::HOST_READ);
+    _device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::PipelineStageFlags::HOST,
+        vk::DependencyFlags::empty(),
+        &[barrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[] as &[vk::ImageMemoryBarrier],
+    );
+
+    return recorder.flush();
+
+}
+
+pub unsafe fn create_framebuffers(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    for &view in &_data.swapchain_image_views {
+        // with MSAA the swapchain image is the resolve target
+        let attachments = &[_data.color_image_view, view];
+        let attachments = match _data.color_image_view.is_null() {
+            true => &attachments[1..],
+            false => &attachments[..],
+        };
+        let info = vk::FramebufferCreateInfo::builder()
+        .render_pass(_data.render_pass)
+        .attachments(attachments)
+        .width(_data.swapchain_extent.width)
+        .height(_data.swapchain_extent.height)
+        .layers(1);
+
+        _data.framebuffers.push(_device.create_framebuffer(&info, None)?);
+        track_created("Framebuffer");
+    }
+
+    return Ok(());
+
+}
+
+/// Creates the timestamp query pool used to time each frame's render pass.
+/// The command buffers are recorded per swapchain image, so each image gets
+/// its own pair of queries; frames in flight never share one.
+pub unsafe fn create_query_pool(_instance: &Instance, _device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let limits = _instance.get_physical_device_properties(_data.physical_device).limits;
+    if limits.timestamp_compute_and_graphics != vk::TRUE {
+        debug!("The device doesn't support timestamps on graphics queues; GPU frame times are unavailable.");
+        return Ok(());
+    }
+    // the render pass is timed on the graphics queue, whose family may still
+    // have no valid timestamp bits
+    let graphics = QueueFamilyIndices::get(_instance, _data, _data.physical_device)?.graphics;
+    let valid_bits = _instance.get_physical_device_queue_family_properties(_data.physical_device)[graphics as usize].timestamp_valid_bits;
+    if valid_bits == 0 {
+        debug!("The graphics queue family has no valid timestamp bits; GPU frame times are unavailable.");
+        return Ok(());
+    }
+
+    let info = vk::QueryPoolCreateInfo::builder()
+    .query_type(vk::QueryType::TIMESTAMP)
+    .query_count(2 * _data.swapchain_images.len() as u32);
+
+    _data.query_pool = _device.create_query_pool(&info, None)?;
+    _data.timestamp_period = limits.timestamp_period;
+    _data.timestamp_valid_bits = valid_bits;
+    track_created("QueryPool");
+
+    return Ok(());
+
+}
+
+/// Converts a pair of timestamps to milliseconds. Only the low `_valid_bits`
+/// of each are meaningful; the rest are undefined.
+pub fn timestamp_delta_ms(_start: u64, _end: u64, _period: f32, _valid_bits: u32) -> Option<f64> {
+    let mask = if _valid_bits >= 64 { u64::MAX } else { (1u64 << _valid_bits) - 1 };
+    let (start, end) = (_start & mask, _end & mask);
+    if _valid_bits == 0 || end < start {
+        return None;
+    }
+    return Some((end - start) as f64 * _period as f64 / 1_000_000.0);
+}
+
+pub unsafe fn create_command_pool(_instance: &Instance, _device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let indices = QueueFamilyIndices::get(_instance, _data, _data.physical_device)?;
+
+    // buffers are re-recorded one at a time when the clear color changes
+    let info = vk::CommandPoolCreateInfo::builder()
+    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+    .queue_family_index(indices.graphics);
+
+    _data.command_pool = _device.create_command_pool(&info, None)?;
+    track_created("CommandPool");
+
+    let info = vk::CommandPoolCreateInfo::builder()
+    .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+    .queue_family_index(indices.transfer);
+
+    _data.transfer_command_pool = _device.create_command_pool(&info, None)?;
+    track_created("CommandPool");
+
+    if let Some(compute) = indices.compute {
+        let info = vk::CommandPoolCreateInfo::builder()
+        .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+        .queue_family_index(compute);
+
+        _data.compute_command_pool = _device.create_command_pool(&info, None)?;
+        track_created("CommandPool");
+    }
+
+    return Ok(());
+
+}
+
+/// Allocates and records one command buffer per framebuffer. Only the clear
+/// color changes between frames, so buffers are recorded up front and
+/// re-recorded individually when it does.
+pub unsafe fn create_command_buffers(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let allocate_info = vk::CommandBufferAllocateInfo::builder()
+    .command_pool(_data.command_pool)
+    .level(vk::CommandBufferLevel::PRIMARY)
+    .command_buffer_count(_data.framebuffers.len() as u32);
+
+    _data.command_buffers = _device.allocate_command_buffers(&allocate_info)?;
+    _data.recorded_clear_colors = vec![_data.clear_color; _data.command_buffers.len()];
+    _data.recorded_stats = vec![DrawStats::default(); _data.command_buffers.len()];
+
+    for i in 0.._data.command_buffers.len() {
+        record_command_buffer(_device, _data, i)?;
+    }
+
+    return Ok(());
+
+}
+
+/// Work recorded into a command buffer. Recording counts it once; each
+/// submission then adds it to `VulkanApp::draw_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DrawStats {
+    pub command_buffers: u64,
+    pub draw_calls: u64,
+    pub triangles: u64
+}
+
+impl DrawStats {
+    pub fn add(&mut self, other: DrawStats) {
+        self.command_buffers += other.command_buffers;
+        self.draw_calls += other.draw_calls;
+        self.triangles += other.triangles;
+    }
+
+    /// The totals averaged over `frames`, for the once-a-second log line.
+    pub fn per_frame(&self, frames: u32) -> String {
+        let frames = frames.max(1) as f64;
+        return format!(
+            "{:.1} command buffers, {:.1} draw calls, {:.0} triangles per frame",
+            self.command_buffers as f64 / frames,
+            self.draw_calls as f64 / frames,
+            self.triangles as f64 / frames,
+        );
+    }
+}
+
+/// Records the command buffer drawing into framebuffer `_index`.
+pub unsafe fn record_command_buffer(_device: &Device, _data: &mut AppData, _index: usize) -> Result<()> {
+
+    let command_buffer = _data.command_buffers[_index];
+    let mut stats = DrawStats { command_buffers: 1, ..Default::default() };
+
+    let begin_info = vk::CommandBufferBeginInfo::builder();
+    _device.begin_command_buffer(command_buffer, &begin_info)?;
+
+    let timestamps = !_data.query_pool.is_null();
+    if timestamps {
+        _device.cmd_reset_query_pool(command_buffer, _data.query_pool, 2 * _index as u32, 2);
+        _device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE, _data.query_pool, 2 * _index as u32);
+    }
+
+    let render_area = vk::Rect2D::builder()
+    .offset(vk::Offset2D::default())
+    .extent(_data.swapchain_extent);
+
+    let color_clear_value = vk::ClearValue {
+        color: clear_color_value(_data.clear_color, _data.swapchain_format),
+    };
+
+    let clear_values = &[color_clear_value];
+    let info = vk::RenderPassBeginInfo::builder()
+    .render_pass(_data.render_pass)
+    .framebuffer(_data.framebuffers[_index])
+    .render_area(render_area)
+    .clear_values(clear_values);
+
+    _device.cmd_begin_render_pass(command_buffer, &info, vk::SubpassContents::INLINE);
+    let pipeline = match _data.wireframe && !_data.wireframe_pipeline.is_null() {
+        true => _data.wireframe_pipeline,
+        false => _data.pipeline,
+    };
+    _device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline);
+    _device.cmd_bind_descriptor_sets(
+        command_buffer,
+        vk::PipelineBindPoint::GRAPHICS,
+        _data.pipeline_layout,
+        0,
+        &[_data.descriptor_sets[_index]],
+        &[],
+    );
+
+    let viewport = vk::Viewport::builder()
+    .x(0.0)
+    .y(0.0)
+    .width(_data.swapchain_extent.width as f32)
+    .height(_data.swapchain_extent.height as f32)
+    .min_depth(0.0)
+    .max_depth(1.0);
+
+    _device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+    _device.cmd_set_scissor(command_buffer, 0, &[render_area]);
+    _device.cmd_bind_vertex_buffers(command_buffer, 0, &[_data.vertex_buffer], &[0]);
+    _device.cmd_bind_index_buffer(command_buffer, _data.index_buffer, 0, vk::IndexType::UINT32);
+    for transform in &_data.object_transforms {
+        _device.cmd_push_constants(command_buffer, _data.pipeline_layout, vk::ShaderStageFlags::VERTEX, 0, push_constant_bytes(transform));
+        _device.cmd_draw_indexed(command_buffer, _data.index_count, 1, 0, 0, 0);
+        stats.draw_calls += 1;
+        stats.triangles += _data.index_count as u64 / 3;
+    }
+    _device.cmd_end_render_pass(command_buffer);
+
+    if timestamps {
+        _device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::BOTTOM_OF_PIPE, _data.query_pool, 2 * _index as u32 + 1);
+    }
+
+    _device.end_command_buffer(command_buffer)?;
+    _data.recorded_clear_colors[_index] = _data.clear_color;
+    _data.recorded_stats[_index] = stats;
+
+    return Ok(());
+
+}
+
+/// The push constants of an object's draw: its model matrix, column-major as
+/// the shader expects.
+pub fn push_constant_bytes(_transform: &Matrix4<f32>) -> &[u8] {
+    return unsafe { std::slice::from_raw_parts((_transform as *const Matrix4<f32>).cast::<u8>(), PUSH_CONSTANTS_SIZE as usize) };
+}
+
+/// A slowly cycling dark background, `_seconds` after startup: each channel
+/// swings around the default gray with its own phase. The swing is even in
+/// displayed brightness, so it's done on the encoded value.
+pub fn animated_clear_color(_seconds: f32) -> [f32; 4] {
+    let base = linear_to_srgb(CLEAR_COLOR[0]);
+    let channel = |phase: f32| srgb_to_linear(base + 0.08 * (_seconds * 0.5 + phase).sin());
+    return [
+        channel(0.0),
+        channel(2.0 * std::f32::consts::FRAC_PI_3),
+        channel(4.0 * std::f32::consts::FRAC_PI_3),
+        1.0,
+    ];
+}
+
+pub unsafe fn create_sync_objects(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let semaphore_info = vk::SemaphoreCreateInfo::builder();
+    // created signaled so the first frame doesn't wait on a submission that never happened
+    let fence_info = vk::FenceCreateInfo::builder()
+    .flags(vk::FenceCreateFlags::SIGNALED);
+
+    for _ in 0..MAX_FRAMES_IN_FLIGHT {
+        _data.image_available_semaphores.push(_device.create_semaphore(&semaphore_info, None)?);
+        track_created("Semaphore");
+        _data.render_finished_semaphores.push(_device.create_semaphore(&semaphore_info, None)?);
+        track_created("Semaphore");
+        _data.in_flight_fences.push(_device.create_fence(&fence_info, None)?);
+        track_created("Fence");
+    }
+
+    return Ok(());
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_convert_to_milliseconds() {
+        assert_eq!(timestamp_delta_ms(1_000, 3_000, 1.0, 64), Some(0.002));
+        assert_eq!(timestamp_delta_ms(0, 1_000_000, 2.5, 64), Some(2.5));
+        assert_eq!(timestamp_delta_ms(5, 1, 1.0, 64), None);
+
+        // undefined high bits are ignored
+        assert_eq!(timestamp_delta_ms(0xff00_0000_0000_1000, 0x1200_0000_0000_3000, 1.0, 36), Some(0.008192));
+        assert_eq!(timestamp_delta_ms(1_000, 3_000, 1.0, 0), None);
+    }
+
+    #[test]
+    fn draw_stats_average_over_frames() {
+        let mut stats = DrawStats::default();
+        let frame = DrawStats { command_buffers: 1, draw_calls: 2, triangles: 12 };
+        stats.add(frame);
+        stats.add(frame);
+        assert_eq!(stats, DrawStats { command_buffers: 2, draw_calls: 4, triangles: 24 });
+        assert_eq!(stats.per_frame(2), "1.0 command buffers, 2.0 draw calls, 12 triangles per frame");
+        assert_eq!(DrawStats::default().per_frame(0), "0.0 command buffers, 0.0 draw calls, 0 triangles per frame");
+    }
+
+    #[test]
+    fn push_constants_hold_the_model_matrix_by_column() {
+        use cgmath::Vector3;
+
+        let transform = Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0));
+        let bytes = push_constant_bytes(&transform);
+        assert_eq!(bytes.len(), 64);
+
+        let float = |i: usize| f32::from_ne_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+        // the translation is the last column
+        assert_eq!((float(12), float(13), float(14), float(15)), (1.0, 2.0, 3.0, 1.0));
+    }
+
+    #[test]
+    fn recorder_batches_copies_into_one_submission() {
+        use crate::memory::{MemoryLocation, create_buffer, destroy_buffer, stage_upload, finish_upload};
+        use crate::memory::tests::test_device;
+
+        let Some((instance, device, mut data)) = (unsafe { test_device() }) else {
+            eprintln!("No Vulkan device available, skipping.");
+            return;
+        };
+
+        unsafe {
+            // an abandoned batch gives its command buffer back
+            let mut abandoned = CommandRecorder::transfer(&data).begin(&device).unwrap();
+            abandoned.record();
+            drop(abandoned);
+
+            let first: Vec<u8> = (0..64).collect();
+            let second: Vec<u8> = (64..128).collect();
+            let usage = vk::BufferUsageFlags::TRANSFER_SRC;
+            let mut recorder = CommandRecorder::transfer(&data).begin(&device).unwrap();
+            let uploads = [
+                stage_upload(&device, &mut data, &mut recorder, "first", &first, usage).unwrap(),
+                stage_upload(&device, &mut data, &mut recorder, "second", &second, usage).unwrap(),
+            ];
+            let (readback, readback_allocation) = create_buffer(
+                &device,
+                &mut data,
+                "readback buffer",
+                128,
+                vk::BufferUsageFlags::TRANSFER_DST,
+                MemoryLocation::GpuToCpu,
+            ).unwrap();
+            assert_eq!(recorder.operations, 2);
+            recorder.flush().unwrap();
+
+            let buffers = uploads.map(|u| finish_upload(&device, &mut data, u));
+            let mut recorder = CommandRecorder::transfer(&data).begin(&device).unwrap();
+            for (index, &(buffer, _)) in buffers.iter().enumerate() {
+                let region = vk::BufferCopy::builder().dst_offset(64 * index as u64).size(64);
+                device.cmd_copy_buffer(recorder.record(), buffer, readback, &[region]);
+            }
+            recorder.flush().unwrap();
+            for (buffer, allocation) in buffers {
+                destroy_buffer(&device, &mut data, buffer, allocation);
+            }
+
+            let read = std::slice::from_raw_parts(readback_allocation.mapped, 128);
+            assert_eq!(&read[..64], &first[..]);
+            assert_eq!(&read[64..], &second[..]);
+
+            destroy_buffer(&device, &mut data, readback, readback_allocation);
+            device.destroy_command_pool(data.transfer_command_pool, None);
+            data.allocator.destroy(&device);
+            device.destroy_device(None);
+            instance.destroy_instance(None);
+        }
+    }
+
+    #[test]
+    fn compute_dispatch_writes_storage_buffer() {
+        use std::path::Path;
+
+        use crate::memory::{MemoryLocation, create_buffer, destroy_buffer};
+        use crate::memory::tests::test_device;
+        use crate::pipeline::{COMP_LOCAL_SIZE, COMP_SHADER_PATH, create_compute_pipeline};
+
+        let Some((instance, device, mut data)) = (unsafe { test_device() }) else {
+            eprintln!("No Vulkan device available, skipping.");
+            return;
+        };
+
+        unsafe {
+            // the test device's only queue doubles as the compute queue
+            let family = data.buffer_queue_families[0];
+            let properties = instance.get_physical_device_queue_family_properties(data.physical_device);
+            if properties[family as usize].queue_flags.contains(vk::QueueFlags::COMPUTE) {
+                data.compute_queue = data.transfer_queue;
+                data.compute_command_pool = data.transfer_command_pool;
+            } else {
+                eprintln!("The test queue family has no compute support, skipping the dispatch.");
+            }
+
+            const COUNT: u32 = 4 * COMP_LOCAL_SIZE;
+            let (buffer, allocation) = create_buffer(
+                &device,
+                &mut data,
+                "compute output",
+                COUNT as u64 * 4,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                MemoryLocation::GpuToCpu,
+            ).unwrap();
+
+            let bindings = &[vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)];
+            let layout_info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .bindings(bindings);
+            let set_layout = device.create_descriptor_set_layout(&layout_info, None).unwrap();
+
+            let pool_sizes = &[vk::DescriptorPoolSize::builder()
+            .type_(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)];
+            let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .pool_sizes(pool_sizes)
+            .max_sets(1);
+            let pool = device.create_descriptor_pool(&pool_info, None).unwrap();
+
+            let set_layouts = &[set_layout];
+            let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(set_layouts);
+            let set = device.allocate_descriptor_sets(&allocate_info).unwrap()[0];
+
+            let buffer_info = &[vk::DescriptorBufferInfo::builder()
+            .buffer(buffer)
+            .offset(0)
+            .range(COUNT as u64 * 4)];
+            let write = vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(buffer_info);
+            device.update_descriptor_sets(&[write], &[] as &[vk::CopyDescriptorSet]);
+
+            let shader_path = Path::new(env!("CARGO_MANIFEST_DIR")).join(COMP_SHADER_PATH);
+            let (layout, pipeline) = create_compute_pipeline(&device, &data, &shader_path, "main", set_layouts).unwrap();
+
+            let result = dispatch(&device, &data, pipeline, layout, &[set], COUNT / COMP_LOCAL_SIZE, 1, 1);
+            if data.compute_queue.is_null() {
+                assert!(result.is_err());
+            } else {
+                result.unwrap();
+                let values = std::slice::from_raw_parts(allocation.mapped as *const u32, COUNT as usize);
+                for (i, &value) in values.iter().enumerate() {
+                    assert_eq!(value, 2 * i as u32 + 1);
+                }
+            }
+
+            device.destroy_pipeline(pipeline, None);
+            track_destroyed("Pipeline");
+            device.destroy_pipeline_layout(layout, None);
+            track_destroyed("PipelineLayout");
+            device.destroy_descriptor_pool(pool, None);
+            device.destroy_descriptor_set_layout(set_layout, None);
+            destroy_buffer(&device, &mut data, buffer, allocation);
+            device.destroy_command_pool(data.transfer_command_pool, None);
+            data.allocator.destroy(&device);
+            device.destroy_device(None);
+            instance.destroy_instance(None);
+        }
+    }
+
+    #[test]
+    fn clear_color_animation_stays_dark() {
+        let start = animated_clear_color(0.0);
+        assert_ne!(start, animated_clear_color(1.0));
+        for t in 0..100 {
+            let color = animated_clear_color(t as f32 * 0.37);
+            assert!(color[..3].iter().all(|&c| (0.0..=0.2).contains(&c)));
+            assert_eq!(color[3], 1.0);
+        }
+    }
+}
@@ -0,0 +1,454 @@
+//! Startup configuration from the command line and environment.
+
+use anyhow::{anyhow, Result};
+
+use vulkanalia::prelude::v1_0::*;
+
+use std::ffi::CString;
+use std::path::PathBuf;
+
+use crate::instance::VALIDATION_DEFAULT;
+use crate::device::parse_queue_priority;
+use crate::swapchain::PresentMode;
+use crate::pipeline::EntryPoints;
+
+pub const DEFAULT_WINDOW_TITLE: &str = "Vulkan Testin (Rust)";
+
+/// Startup settings. Each one is read from `--<name>=<value>` (or a bare
+/// `--<name>` for switches) on the command line, then from its `VULCAN_*`
+/// environment variable, then defaults.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AppConfig {
+    // initial window size in logical pixels (--width, --height)
+    pub width: u32,
+    pub height: u32,
+    // whether either was given, so the saved window size doesn't override it
+    pub explicit_size: bool,
+    // --title
+    pub title: String,
+    // validation layers and debug messenger (--validation=1|0|on|off)
+    pub validation: bool,
+    // preferred present mode (--present-mode=fifo|mailbox|immediate)
+    pub present_mode: PresentMode,
+    // queue global priority to ask for (--queue-priority=<level>)
+    pub queue_priority: Option<vk::QueueGlobalPriorityKHR>,
+    // render offscreen at this size without a window (--headless=WIDTHxHEIGHT)
+    pub headless: Option<vk::Extent2D>,
+    // use this physical device (index as logged at startup) instead of
+    // picking one (--gpu=N)
+    pub gpu: Option<usize>,
+    // attempts at recreating the device after VK_ERROR_DEVICE_LOST before
+    // giving up (--device-recoveries=N)
+    pub device_recoveries: u32,
+    // OBJ file to draw instead of the built-in triangle (--model=PATH)
+    pub model: Option<PathBuf>,
+    // MSAA samples per pixel, clamped to what the device supports (--msaa=N)
+    pub msaa: u32,
+    // cap on frames per second, on top of whatever the present mode does
+    // (--max-fps=N)
+    pub max_fps: Option<u32>,
+    // print the Vulkan devices and their capabilities, then exit (--info,
+    // VULCAN_INFO=1)
+    pub info: bool,
+    // on-demand rendering: only redraw after something changed (--idle)
+    pub idle: bool,
+    // ignore the saved window geometry (--reset-window)
+    pub reset_window: bool,
+    // rebuild the pipeline when the SPIR-V files change, debug builds only
+    // (--hot-reload)
+    pub hot_reload: bool,
+    // slowly cycle the background color instead of keeping the one set
+    // (--animate-clear)
+    pub animate_clear: bool,
+    // shader functions the pipeline starts in (--vertex-entry,
+    // --fragment-entry)
+    pub entry_points: EntryPoints,
+    // application and engine reported to the driver (--app-name,
+    // --app-version, --engine-name, --engine-version)
+    pub identity: AppIdentity
+}
+
+/// Options taking a value, as `--<name>=<value>`.
+pub const VALUE_OPTIONS: &[&str] = &[
+    "width", "height", "title", "validation", "present-mode", "queue-priority", "headless", "gpu",
+    "model", "msaa", "max-fps", "device-recoveries", "vertex-entry", "fragment-entry",
+    "app-name", "app-version", "engine-name", "engine-version",
+];
+
+/// Switches given as a bare `--<name>`.
+pub const SWITCHES: &[&str] = &["info", "idle", "reset-window", "hot-reload", "animate-clear"];
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        return Self {
+            width: 1280,
+            height: 720,
+            explicit_size: false,
+            title: DEFAULT_WINDOW_TITLE.to_string(),
+            validation: VALIDATION_DEFAULT,
+            present_mode: PresentMode::default(),
+            queue_priority: None,
+            headless: None,
+            gpu: None,
+            device_recoveries: 3,
+            model: None,
+            msaa: 4,
+            max_fps: None,
+            info: false,
+            idle: false,
+            reset_window: false,
+            hot_reload: false,
+            animate_clear: false,
+            entry_points: EntryPoints::default(),
+            identity: AppIdentity::default()
+        };
+    }
+}
+
+impl AppConfig {
+    pub fn load() -> Result<Self> {
+        let args = std::env::args().collect::<Vec<_>>();
+        return Self::parse(&args, |name| std::env::var(name).ok());
+    }
+
+    pub fn parse(_args: &[String], _env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        check_options(_args)?;
+
+        let value = |name: &str, var: &str| {
+            let prefix = format!("--{}=", name);
+            return _args
+            .iter()
+            .find_map(|a| a.strip_prefix(&prefix).map(String::from))
+            .or_else(|| _env(var));
+        };
+        let size = |name: &str, var: &str| {
+            return value(name, var)
+            .map(|v| match v.parse::<u32>() {
+                Ok(size) if size > 0 => Ok(size),
+                _ => Err(anyhow!("--{} must be a positive integer, not {:?}.", name, v)),
+            })
+            .transpose();
+        };
+        let switch = |name: &str, var: &str| {
+            if _args.iter().any(|a| *a == format!("--{}", name)) {
+                return Ok(true);
+            }
+            return match _env(var) {
+                Some(v) => parse_validation_flag(&v).map_err(|_| anyhow!("{} must be 1, 0, on or off, not {:?}.", var, v)),
+                None => Ok(false),
+            };
+        };
+
+        let mut config = Self::default();
+        if let Some(width) = size("width", "VULCAN_WIDTH")? {
+            config.width = width;
+            config.explicit_size = true;
+        }
+        if let Some(height) = size("height", "VULCAN_HEIGHT")? {
+            config.height = height;
+            config.explicit_size = true;
+        }
+        if let Some(title) = value("title", "VULCAN_TITLE") {
+            config.title = title;
+        }
+        if let Some(validation) = value("validation", "VULCAN_VALIDATION") {
+            config.validation = parse_validation_flag(&validation)?;
+        }
+        if let Some(mode) = value("present-mode", "VULCAN_PRESENT_MODE") {
+            config.present_mode = PresentMode::parse(&mode)?;
+        }
+        if let Some(level) = value("queue-priority", "VULCAN_QUEUE_PRIORITY") {
+            config.queue_priority = Some(parse_queue_priority(&level)?);
+        }
+        if let Some(size) = value("headless", "VULCAN_HEADLESS") {
+            config.headless = Some(parse_headless_size(&size)?);
+        }
+        if let Some(gpu) = value("gpu", "VULCAN_GPU") {
+            config.gpu = Some(gpu.parse().map_err(|_| anyhow!("--gpu must be a device index, not {:?}.", gpu))?);
+        }
+        if let Some(path) = value("model", "VULCAN_MODEL") {
+            config.model = Some(PathBuf::from(path));
+        }
+        if let Some(samples) = value("msaa", "VULCAN_MSAA") {
+            config.msaa = parse_msaa_samples(&samples)?;
+        }
+        if let Some(fps) = size("max-fps", "VULCAN_MAX_FPS")? {
+            config.max_fps = Some(fps);
+        }
+        if let Some(count) = value("device-recoveries", "VULCAN_DEVICE_RECOVERIES") {
+            config.device_recoveries = count.parse().map_err(|_| anyhow!("--device-recoveries must be a count, not {:?}.", count))?;
+        }
+        if let Some(name) = value("vertex-entry", "VULCAN_VERTEX_ENTRY") {
+            config.entry_points.vertex = parse_entry_point(&name)?;
+        }
+        if let Some(name) = value("fragment-entry", "VULCAN_FRAGMENT_ENTRY") {
+            config.entry_points.fragment = parse_entry_point(&name)?;
+        }
+        if let Some(name) = value("app-name", "VULCAN_APP_NAME") {
+            config.identity.app_name = parse_identity_name(&name)?;
+        }
+        if let Some(version) = value("app-version", "VULCAN_APP_VERSION") {
+            config.identity.app_version = parse_identity_version(&version)?;
+        }
+        if let Some(name) = value("engine-name", "VULCAN_ENGINE_NAME") {
+            config.identity.engine_name = parse_identity_name(&name)?;
+        }
+        if let Some(version) = value("engine-version", "VULCAN_ENGINE_VERSION") {
+            config.identity.engine_version = parse_identity_version(&version)?;
+        }
+        config.info = switch("info", "VULCAN_INFO")?;
+        config.idle = switch("idle", "VULCAN_IDLE")?;
+        config.reset_window = switch("reset-window", "VULCAN_RESET_WINDOW")?;
+        config.hot_reload = switch("hot-reload", "VULCAN_HOT_RELOAD")?;
+        config.animate_clear = switch("animate-clear", "VULCAN_ANIMATE_CLEAR")?;
+
+        return Ok(config);
+    }
+}
+
+/// Rejects `--` arguments that aren't a known option, so a typo doesn't
+/// silently fall back to the default. Anything else (the program name) is
+/// left alone.
+pub fn check_options(_args: &[String]) -> Result<()> {
+    for arg in _args {
+        let Some(option) = arg.strip_prefix("--") else {
+            continue;
+        };
+        let known = match option.split_once('=') {
+            Some((name, _)) => VALUE_OPTIONS.contains(&name),
+            None => SWITCHES.contains(&option),
+        };
+        if !known {
+            return Err(anyhow!("Unknown option {:?}.", arg));
+        }
+    }
+
+    return Ok(());
+}
+
+/// An entry point name, checked early for what `entry_point_name` would
+/// refuse later.
+pub fn parse_entry_point(_name: &str) -> Result<String> {
+    if _name.is_empty() || _name.contains('\0') {
+        return Err(anyhow!("Entry point names must be non-empty without NUL bytes, not {:?}.", _name));
+    }
+    return Ok(_name.to_string());
+}
+
+/// Parses a `WIDTHxHEIGHT` offscreen target size.
+pub fn parse_headless_size(_size: &str) -> Result<vk::Extent2D> {
+    let parsed = _size
+    .split_once('x')
+    .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+    .filter(|&(w, h)| w > 0 && h > 0);
+
+    return match parsed {
+        Some((width, height)) => Ok(vk::Extent2D { width, height }),
+        None => Err(anyhow!("--headless must be WIDTHxHEIGHT, not {:?}.", _size)),
+    };
+}
+
+/// Parses an MSAA sample count: a power of two from 1 (off) to 64.
+pub fn parse_msaa_samples(_samples: &str) -> Result<u32> {
+    return match _samples.parse::<u32>() {
+        Ok(samples) if samples.is_power_of_two() && samples <= 64 => Ok(samples),
+        _ => Err(anyhow!("--msaa must be 1, 2, 4, 8, 16, 32 or 64, not {:?}.", _samples)),
+    };
+}
+
+/// Application/engine identity reported to the driver in `ApplicationInfo`.
+///
+/// Some drivers key workarounds on these strings, so embedders can override
+/// them with `--app-name`, `--app-version`, `--engine-name` and
+/// `--engine-version` or their `VULCAN_*` variables (versions as
+/// `major.minor.patch`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppIdentity {
+    pub app_name: CString,
+    pub app_version: u32,
+    pub engine_name: CString,
+    pub engine_version: u32
+}
+
+impl Default for AppIdentity {
+    fn default() -> Self {
+        return Self {
+            app_name: CString::new("Vulcan Tutorial").unwrap(),
+            app_version: vk::make_version(1, 0, 0),
+            engine_name: CString::new("No Engine").unwrap(),
+            engine_version: vk::make_version(1, 0, 0)
+        };
+    }
+}
+
+pub fn parse_validation_flag(_value: &str) -> Result<bool> {
+    return match _value.to_ascii_lowercase().as_str() {
+        "1" | "on" => Ok(true),
+        "0" | "off" => Ok(false),
+        _ => Err(anyhow!("Validation must be 1, 0, on or off, not {:?}.", _value)),
+    };
+}
+
+pub fn parse_identity_name(_name: &str) -> Result<CString> {
+    return CString::new(_name).map_err(|_| anyhow!("Name {:?} must not contain NUL bytes.", _name));
+}
+
+pub fn parse_identity_version(_version: &str) -> Result<u32> {
+    let parts = _version
+    .split('.')
+    .map(|p| p.trim().parse::<u32>())
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|_| anyhow!("Invalid version {:?}, expected major.minor.patch.", _version))?;
+
+    match parts.as_slice() {
+        [major, minor, patch] => return Ok(vk::make_version(*major, *minor, *patch)),
+        _ => return Err(anyhow!("Invalid version {:?}, expected major.minor.patch.", _version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_version_parses_triplets() {
+        assert_eq!(parse_identity_version("2.5.1").unwrap(), vk::make_version(2, 5, 1));
+        assert!(parse_identity_version("2.5").is_err());
+        assert!(parse_identity_version("a.b.c").is_err());
+    }
+
+    #[test]
+    fn identity_comes_from_arguments_or_environment() {
+        let env = |name: &str| (name == "VULCAN_ENGINE_NAME").then(|| "Test Engine".to_string());
+        let config = AppConfig::parse(&["--app-version=2.0.1".to_string()], env).unwrap();
+        assert_eq!(config.identity.app_version, vk::make_version(2, 0, 1));
+        assert_eq!(config.identity.engine_name, CString::new("Test Engine").unwrap());
+        assert_eq!(config.identity.app_name, AppIdentity::default().app_name);
+        assert!(AppConfig::parse(&["--engine-version=1".to_string()], |_| None).is_err());
+    }
+
+    #[test]
+    fn identity_name_rejects_interior_nul() {
+        assert!(parse_identity_name("My App").is_ok());
+        assert!(parse_identity_name("My\0App").is_err());
+    }
+
+    #[test]
+    fn validation_flag_parses_on_and_off() {
+        assert!(parse_validation_flag("1").unwrap());
+        assert!(parse_validation_flag("ON").unwrap());
+        assert!(!parse_validation_flag("off").unwrap());
+        assert!(parse_validation_flag("yes").is_err());
+    }
+
+    #[test]
+    fn config_prefers_arguments_over_environment() {
+        let args = ["vulcan_test", "--width=800", "--present-mode=mailbox"].map(String::from);
+        let env = |name: &str| match name {
+            "VULCAN_WIDTH" => Some("640".to_string()),
+            "VULCAN_TITLE" => Some("Test".to_string()),
+            _ => None,
+        };
+
+        let config = AppConfig::parse(&args, env).unwrap();
+        assert_eq!((config.width, config.height), (800, 720));
+        assert_eq!(config.title, "Test");
+        assert_eq!(config.present_mode, PresentMode::Mailbox);
+
+        assert_eq!(AppConfig::parse(&[], |_| None).unwrap(), AppConfig::default());
+    }
+
+    #[test]
+    fn window_size_must_be_positive() {
+        assert!(AppConfig::parse(&["--height=0".to_string()], |_| None).is_err());
+        assert!(AppConfig::parse(&["--width=wide".to_string()], |_| None).is_err());
+    }
+
+    #[test]
+    fn model_path_is_optional() {
+        assert_eq!(AppConfig::parse(&[], |_| None).unwrap().model, None);
+        let model = AppConfig::parse(&["--model=models/cube.obj".to_string()], |_| None).unwrap();
+        assert_eq!(model.model, Some(PathBuf::from("models/cube.obj")));
+    }
+
+    #[test]
+    fn gpu_must_be_a_device_index() {
+        let gpu = |name: &str| (name == "VULCAN_GPU").then(|| "1".to_string());
+        assert_eq!(AppConfig::parse(&[], gpu).unwrap().gpu, Some(1));
+        assert!(AppConfig::parse(&["--gpu=first".to_string()], |_| None).is_err());
+    }
+
+    #[test]
+    fn device_recoveries_must_be_a_count() {
+        assert_eq!(AppConfig::parse(&["--device-recoveries=0".to_string()], |_| None).unwrap().device_recoveries, 0);
+        assert!(AppConfig::parse(&["--device-recoveries=-1".to_string()], |_| None).is_err());
+    }
+
+    #[test]
+    fn max_fps_must_be_positive() {
+        let fps = |name: &str| (name == "VULCAN_MAX_FPS").then(|| "120".to_string());
+        assert_eq!(AppConfig::parse(&[], fps).unwrap().max_fps, Some(120));
+        assert!(AppConfig::parse(&["--max-fps=0".to_string()], |_| None).is_err());
+    }
+
+    #[test]
+    fn entry_points_default_to_main() {
+        assert_eq!(AppConfig::parse(&[], |_| None).unwrap().entry_points, EntryPoints::default());
+        let env = |name: &str| (name == "VULCAN_FRAGMENT_ENTRY").then(|| "shade".to_string());
+        let config = AppConfig::parse(&["--vertex-entry=transform".to_string()], env).unwrap();
+        assert_eq!((config.entry_points.vertex.as_str(), config.entry_points.fragment.as_str()), ("transform", "shade"));
+        assert!(AppConfig::parse(&["--vertex-entry=".to_string()], |_| None).is_err());
+    }
+
+    #[test]
+    fn info_is_a_switch() {
+        assert!(AppConfig::parse(&["--info".to_string()], |_| None).unwrap().info);
+        let info = |name: &str| (name == "VULCAN_INFO").then(|| "1".to_string());
+        assert!(AppConfig::parse(&[], info).unwrap().info);
+    }
+
+    #[test]
+    fn switches_come_from_bare_arguments_or_environment() {
+        let config = AppConfig::parse(&["--idle".to_string(), "--hot-reload".to_string()], |_| None).unwrap();
+        assert!(config.idle && config.hot_reload && !config.reset_window && !config.animate_clear);
+        assert!(AppConfig::parse(&["--animate-clear".to_string()], |_| None).unwrap().animate_clear);
+
+        let env = |name: &str| (name == "VULCAN_RESET_WINDOW").then(|| "on".to_string());
+        assert!(AppConfig::parse(&[], env).unwrap().reset_window);
+        let env = |name: &str| (name == "VULCAN_IDLE").then(|| "maybe".to_string());
+        assert!(AppConfig::parse(&[], env).is_err());
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        assert!(AppConfig::parse(&["vulcan_test".to_string(), "--idle".to_string()], |_| None).is_ok());
+        assert!(AppConfig::parse(&["--idel".to_string()], |_| None).is_err());
+        assert!(AppConfig::parse(&["--idle=1".to_string()], |_| None).is_err());
+        assert!(AppConfig::parse(&["--width".to_string()], |_| None).is_err());
+    }
+
+    #[test]
+    fn window_size_is_explicit_only_when_given() {
+        assert!(!AppConfig::parse(&[], |_| None).unwrap().explicit_size);
+        assert!(AppConfig::parse(&["--height=600".to_string()], |_| None).unwrap().explicit_size);
+        let width = |name: &str| (name == "VULCAN_WIDTH").then(|| "640".to_string());
+        assert!(AppConfig::parse(&[], width).unwrap().explicit_size);
+    }
+
+    #[test]
+    fn msaa_samples_must_be_a_power_of_two() {
+        assert_eq!(parse_msaa_samples("1").unwrap(), 1);
+        assert_eq!(parse_msaa_samples("8").unwrap(), 8);
+        assert!(parse_msaa_samples("3").is_err());
+        assert!(parse_msaa_samples("0").is_err());
+        assert!(parse_msaa_samples("128").is_err());
+    }
+
+    #[test]
+    fn headless_size_parses_width_by_height() {
+        assert_eq!(parse_headless_size("640x480").unwrap(), vk::Extent2D { width: 640, height: 480 });
+        assert!(parse_headless_size("640").is_err());
+        assert!(parse_headless_size("0x480").is_err());
+        assert!(parse_headless_size("640x-1").is_err());
+    }
+}
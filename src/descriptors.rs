@@ -0,0 +1,152 @@
+//! Descriptor sets and the uniform buffers they point at.
+
+use anyhow::Result;
+
+use cgmath::Matrix4;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::app::AppData;
+use crate::memory::{MemoryLocation, create_buffer, destroy_buffer};
+use crate::handles::{track_created, track_destroyed};
+
+/// Uniform block of the vertex shader (set 0, binding 0).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UniformBufferObject {
+    pub model: Matrix4<f32>,
+    pub view: Matrix4<f32>,
+    pub proj: Matrix4<f32>
+}
+
+pub unsafe fn create_descriptor_set_layout(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let ubo_binding = vk::DescriptorSetLayoutBinding::builder()
+    .binding(0)
+    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+    .descriptor_count(1)
+    .stage_flags(vk::ShaderStageFlags::VERTEX);
+
+    let bindings = &[ubo_binding];
+    let info = vk::DescriptorSetLayoutCreateInfo::builder()
+    .bindings(bindings);
+
+    _data.descriptor_set_layout = _device.create_descriptor_set_layout(&info, None)?;
+    track_created("DescriptorSetLayout");
+
+    return Ok(());
+
+}
+
+/// Creates a persistently mapped uniform buffer per swapchain image, so a
+/// frame can be written while the GPU still reads another image's. They
+/// follow the command buffers, which are recorded per image and bind the
+/// image's set, rather than the frames in flight; swapchain recreation
+/// rebuilds them along with the pool and sets.
+pub unsafe fn create_uniform_buffers(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    for _ in 0.._data.swapchain_images.len() {
+        let (buffer, allocation) = create_buffer(
+            _device,
+            _data,
+            "uniform buffer",
+            std::mem::size_of::<UniformBufferObject>() as u64,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            MemoryLocation::CpuToGpu,
+        )?;
+        _data.uniform_buffers.push(buffer);
+        _data.uniform_allocations.push(allocation);
+    }
+
+    return Ok(());
+
+}
+
+pub unsafe fn create_descriptor_pool(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let count = _data.swapchain_images.len() as u32;
+    let ubo_size = vk::DescriptorPoolSize::builder()
+    .type_(vk::DescriptorType::UNIFORM_BUFFER)
+    .descriptor_count(count);
+
+    let pool_sizes = &[ubo_size];
+    let info = vk::DescriptorPoolCreateInfo::builder()
+    .pool_sizes(pool_sizes)
+    .max_sets(count);
+
+    _data.descriptor_pool = _device.create_descriptor_pool(&info, None)?;
+    track_created("DescriptorPool");
+
+    return Ok(());
+
+}
+
+/// Allocates a descriptor set per uniform buffer and points it at the buffer.
+/// The sets go away with the pool.
+pub unsafe fn create_descriptor_sets(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let layouts = vec![_data.descriptor_set_layout; _data.uniform_buffers.len()];
+    let info = vk::DescriptorSetAllocateInfo::builder()
+    .descriptor_pool(_data.descriptor_pool)
+    .set_layouts(&layouts);
+
+    _data.descriptor_sets = _device.allocate_descriptor_sets(&info)?;
+
+    for (&set, &buffer) in _data.descriptor_sets.iter().zip(&_data.uniform_buffers) {
+        let info = vk::DescriptorBufferInfo::builder()
+        .buffer(buffer)
+        .offset(0)
+        .range(std::mem::size_of::<UniformBufferObject>() as u64);
+
+        let buffer_info = &[info];
+        let ubo_write = vk::WriteDescriptorSet::builder()
+        .dst_set(set)
+        .dst_binding(0)
+        .dst_array_element(0)
+        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+        .buffer_info(buffer_info);
+
+        _device.update_descriptor_sets(&[ubo_write], &[] as &[vk::CopyDescriptorSet]);
+    }
+
+    return Ok(());
+
+}
+
+/// Writes `_ubo` into the uniform buffer of swapchain image `_index`. The
+/// memory is host coherent, so there's nothing to flush.
+pub unsafe fn write_uniform_buffer(_data: &AppData, _index: usize, _ubo: &UniformBufferObject) {
+    let mapped = _data.uniform_allocations[_index].mapped;
+    std::ptr::copy_nonoverlapping(_ubo, mapped.cast(), 1);
+}
+
+/// Destroys the uniform buffers and descriptor pool, which are sized to the
+/// swapchain. The set layout lives as long as the device.
+pub unsafe fn destroy_uniform_objects(_device: &Device, _data: &mut AppData) {
+
+    _data.descriptor_sets.clear();
+    if !_data.descriptor_pool.is_null() {
+        _device.destroy_descriptor_pool(_data.descriptor_pool, None);
+        _data.descriptor_pool = vk::DescriptorPool::null();
+        track_destroyed("DescriptorPool");
+    }
+
+    let buffers = std::mem::take(&mut _data.uniform_buffers);
+    let allocations = std::mem::take(&mut _data.uniform_allocations);
+    for (buffer, allocation) in buffers.into_iter().zip(allocations) {
+        destroy_buffer(_device, _data, buffer, allocation);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uniform_block_matches_the_std140_layout() {
+        // three column-major mat4s: 64 bytes each, no padding
+        assert_eq!(std::mem::size_of::<UniformBufferObject>(), 192);
+        assert_eq!(std::mem::align_of::<UniformBufferObject>(), 4);
+    }
+}
@@ -0,0 +1,1043 @@
+//! Physical device selection and logical device creation.
+
+use anyhow::{anyhow, Context, Result};
+use thiserror::Error;
+
+use log::*;
+
+use vk::QueueFamilyProperties;
+
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::Version;
+use vulkanalia::vk::KhrGetPhysicalDeviceProperties2Extension;
+use vulkanalia::vk::InstanceV1_1;
+use vulkanalia::vk::KhrSurfaceExtension;
+
+use std::collections::HashSet;
+
+use crate::app::AppData;
+use crate::instance::{API_VERSION, VALIDATION_LAYER, names_from_ptrs, use_portability};
+use crate::handles::track_created;
+use crate::pipeline::PUSH_CONSTANTS_SIZE;
+
+// device extensions every candidate must support
+pub const DEVICE_EXTENSIONS: &[vk::ExtensionName] = &[vk::KHR_SWAPCHAIN_EXTENSION.name];
+pub const SOFTWARE_RENDERERS: &[&str] = &["llvmpipe", "lavapipe", "swiftshader"];
+
+#[derive(Debug, Error)]
+#[error("Missing {0}.")]
+pub struct SuitabilityError(pub &'static str);
+
+/// Device features the app can't run without. Devices missing any are
+/// rejected, and the device is created with these enabled on top of
+/// `PREFERRED_FEATURES`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RequiredFeatures {
+    pub sampler_anisotropy: bool,
+    // wireframe rendering
+    pub fill_mode_non_solid: bool,
+    pub geometry_shader: bool,
+    // lines wider than one pixel
+    pub wide_lines: bool
+}
+
+// enabled whenever the device has them, but never required; MoltenVK and many
+// mobile GPUs lack geometry shaders, and wireframe and anisotropic filtering
+// switch themselves off without their features
+pub const PREFERRED_FEATURES: RequiredFeatures = RequiredFeatures {
+    sampler_anisotropy: true,
+    fill_mode_non_solid: true,
+    geometry_shader: true,
+    wide_lines: false
+};
+
+// where a feature lives in `vk::PhysicalDeviceFeatures`
+pub type FeatureField = fn(&mut vk::PhysicalDeviceFeatures) -> &mut vk::Bool32;
+
+impl RequiredFeatures {
+    /// The features as Vulkan names, each with whether it's set here.
+    pub fn entries(&self) -> [(&'static str, bool, FeatureField); 4] {
+        return [
+            ("samplerAnisotropy", self.sampler_anisotropy, |f| &mut f.sampler_anisotropy),
+            ("fillModeNonSolid", self.fill_mode_non_solid, |f| &mut f.fill_mode_non_solid),
+            ("geometryShader", self.geometry_shader, |f| &mut f.geometry_shader),
+            ("wideLines", self.wide_lines, |f| &mut f.wide_lines),
+        ];
+    }
+
+    /// Names of the features set here that `_supported` lacks.
+    pub fn missing(&self, _supported: &vk::PhysicalDeviceFeatures) -> Vec<&'static str> {
+        let mut supported = *_supported;
+        return self.entries()
+        .into_iter()
+        .filter(|&(_, wanted, field)| wanted && *field(&mut supported) != vk::TRUE)
+        .map(|(name, _, _)| name)
+        .collect();
+    }
+
+    /// The features to create the device with: every required one, and each
+    /// of `_preferred` that `_supported` has.
+    pub fn enabled(&self, _preferred: &RequiredFeatures, _supported: &vk::PhysicalDeviceFeatures) -> vk::PhysicalDeviceFeatures {
+        let mut supported = *_supported;
+        let mut enabled = vk::PhysicalDeviceFeatures::default();
+        for ((_, required, field), (_, preferred, _)) in self.entries().into_iter().zip(_preferred.entries()) {
+            if required || (preferred && *field(&mut supported) == vk::TRUE) {
+                *field(&mut enabled) = vk::TRUE;
+            }
+        }
+        return enabled;
+    }
+}
+
+pub struct QueueFamilyIndices {
+    pub graphics: u32,
+    pub present: u32,
+    // a dedicated transfer family when there is one, otherwise `graphics`
+    pub transfer: u32,
+    // a compute-only family when there is one, otherwise any with COMPUTE
+    pub compute: Option<u32>
+}
+
+impl QueueFamilyIndices {
+    pub fn get(_instance: &Instance, _data: &AppData, _p_device: vk::PhysicalDevice) -> Result<Self> {
+        let properties: Vec<QueueFamilyProperties>;
+        let mut present: Option<u32> = None;
+
+        unsafe {
+            properties = _instance.get_physical_device_queue_family_properties(_p_device);
+
+            // nothing is presented without a surface, and without one the
+            // instance may lack VK_KHR_surface, so don't ask
+            if _data.surface.is_null() {
+                return Self::from_families(&properties, find_graphics_family(&properties));
+            }
+
+            for index in 0..properties.len() as u32 {
+                if present.is_some() {
+                    break;
+                }
+                if _instance.get_physical_device_surface_support_khr(_p_device, index, _data.surface)? {
+                    present = Some(index);
+                }
+            }
+
+        }
+
+        return Self::from_families(&properties, present);
+
+    }
+
+    /// Assigns the roles given the device's queue families and the family
+    /// that can present, if any.
+    pub fn from_families(_properties: &[QueueFamilyProperties], _present: Option<u32>) -> Result<Self> {
+        if let (Some(graphics), Some(present)) = (find_graphics_family(_properties), _present) {
+            let transfer = find_dedicated_transfer_family(_properties).unwrap_or(graphics);
+            let compute = find_compute_family(_properties);
+            return Ok(Self { graphics, present, transfer, compute });
+        } else {
+            return Err(anyhow!(SuitabilityError("Missing required queue families.")))
+        }
+    }
+}
+
+pub fn find_graphics_family(_properties: &[QueueFamilyProperties]) -> Option<u32> {
+    return _properties
+    .iter()
+    .position(|p| p.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+    .map(|i| i as u32);
+}
+
+/// Logs every queue family of the selected device and which one was assigned
+/// to each role, so the choice can be checked on unusual hardware.
+pub unsafe fn log_queue_families(_instance: &Instance, _data: &AppData) -> Result<()> {
+
+    let properties = _instance.get_physical_device_queue_family_properties(_data.physical_device);
+    for (index, family) in properties.iter().enumerate() {
+        debug!(
+            "Queue family {}: {:?}, {} queue(s), {} timestamp valid bits.",
+            index, family.queue_flags, family.queue_count, family.timestamp_valid_bits
+        );
+    }
+
+    let indices = QueueFamilyIndices::get(_instance, _data, _data.physical_device)?;
+    debug!("Graphics queue family: {} (first family with GRAPHICS).", indices.graphics);
+    debug!("Present queue family: {} (first family that can present to the surface).", indices.present);
+    if indices.transfer == indices.graphics {
+        debug!("Transfer queue family: {} (no TRANSFER-only family, sharing graphics).", indices.transfer);
+    } else {
+        debug!("Transfer queue family: {} (first family with TRANSFER but not GRAPHICS).", indices.transfer);
+    }
+    match indices.compute {
+        Some(compute) => debug!("Compute queue family: {} (COMPUTE-only if there is one).", compute),
+        None => debug!("No compute queue family."),
+    }
+
+    return Ok(());
+
+}
+
+/// Finds a family that supports transfers but not graphics, which usually maps
+/// to a DMA engine that can upload while the graphics queue renders.
+pub fn find_dedicated_transfer_family(_properties: &[QueueFamilyProperties]) -> Option<u32> {
+    return _properties
+    .iter()
+    .position(|p| p.queue_flags.contains(vk::QueueFlags::TRANSFER) && !p.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+    .map(|i| i as u32);
+}
+
+/// Prefers a family with COMPUTE but not GRAPHICS (async compute), falling
+/// back to the first family with COMPUTE at all.
+pub fn find_compute_family(_properties: &[QueueFamilyProperties]) -> Option<u32> {
+    return _properties
+    .iter()
+    .position(|p| p.queue_flags.contains(vk::QueueFlags::COMPUTE) && !p.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+    .or_else(|| _properties.iter().position(|p| p.queue_flags.contains(vk::QueueFlags::COMPUTE)))
+    .map(|i| i as u32);
+}
+
+pub unsafe fn pick_physical_device(_instance: &Instance, _data: &mut AppData) -> Result<()> {
+
+    let mut devices = _instance.enumerate_physical_devices()?;
+    if devices.is_empty() {
+        return Err(anyhow!("No Vulkan devices found \u{2014} is a driver installed?"));
+    }
+    devices.sort_by_cached_key(|&d| device_sort_key(&_instance.get_physical_device_properties(d)));
+
+    for (index, &device) in devices.iter().enumerate() {
+        let properties = _instance.get_physical_device_properties(device);
+        info!("Physical device {}: '{}' ({:?}).", index, properties.device_name, properties.device_type);
+    }
+
+    if let Some(index) = _data.forced_gpu {
+        let device = *devices.get(index).ok_or_else(|| {
+            anyhow!("Physical device {} was requested, but only {} were found.", index, devices.len())
+        })?;
+        let properties = _instance.get_physical_device_properties(device);
+        check_physical_device(_instance, _data, device)
+        .with_context(|| format!("Requested physical device {} ('{}') is unsuitable", index, properties.device_name))?;
+
+        select_physical_device(_data, device, &properties, true);
+        return Ok(());
+    }
+
+    // every device that passes the requirements is scored; ties keep the
+    // sorted order, so the choice is the same across runs
+    let mut best: Option<(vk::PhysicalDevice, (bool, u32))> = None;
+    for &device in &devices {
+        let properties = _instance.get_physical_device_properties(device);
+
+        if let Err(error) = check_physical_device(_instance, _data, device) {
+            warn!("Skipping physical device ('{}'): {}", properties.device_name, error);
+            continue;
+        }
+
+        let score = score_physical_device(&properties);
+        debug!("Physical device ('{}') scored {:?} (discrete, max 2D image size).", properties.device_name, score);
+        if best.is_none_or(|(_, b)| score > b) {
+            best = Some((device, score));
+        }
+    }
+
+    if let Some((device, _)) = best {
+        let properties = _instance.get_physical_device_properties(device);
+        select_physical_device(_data, device, &properties, false);
+        return Ok(());
+    }
+
+    // Nothing met every requirement, so settle for the device that misses the
+    // fewest optional ones rather than refusing to run on modest hardware.
+    warn!("No physical device meets all requirements; retrying with optional requirements waived.");
+
+    let mut best: Option<(vk::PhysicalDevice, Vec<SuitabilityError>)> = None;
+    let mut rejected = Vec::new();
+    for &device in &devices {
+        if let Err(error) = check_essential_requirements(_instance, _data, device) {
+            let properties = _instance.get_physical_device_properties(device);
+            rejected.push((properties.device_name.to_string(), format!("{:#}", error)));
+            continue;
+        }
+
+        let unmet = unmet_optional_requirements(_instance, _data, device);
+        if best.as_ref().is_none_or(|(_, b)| unmet.len() < b.len()) {
+            best = Some((device, unmet));
+        }
+    }
+
+    if let Some((device, unmet)) = best {
+        let properties = _instance.get_physical_device_properties(device);
+        for error in &unmet {
+            warn!("Waiving requirement for physical device ('{}'): {}", properties.device_name, error);
+        }
+        select_physical_device(_data, device, &properties, false);
+        return Ok(());
+    }
+
+    for (name, reason) in &rejected {
+        error!("Rejected physical device ('{}'): {}", name, reason);
+    }
+    return Err(anyhow!(no_suitable_device_message(&rejected)));
+
+}
+
+/// Error for when every device failed an essential requirement, naming each
+/// with the reason it was rejected.
+pub fn no_suitable_device_message(_rejected: &[(String, String)]) -> String {
+    let reasons = _rejected
+    .iter()
+    .map(|(name, reason)| format!("'{}': {}", name, reason))
+    .collect::<Vec<_>>();
+    return format!("None of the {} Vulkan device(s) is suitable ({}).", _rejected.len(), reasons.join("; "));
+}
+
+/// Sort key giving enumerated devices a stable order across runs, which the
+/// driver's enumeration order does not guarantee.
+pub fn device_sort_key(_properties: &vk::PhysicalDeviceProperties) -> (u32, u32, u32, String) {
+
+    let type_priority = match _properties.device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 0,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 1,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+        vk::PhysicalDeviceType::CPU => 3,
+        _ => 4
+    };
+
+    return (type_priority, _properties.vendor_id, _properties.device_id, _properties.device_name.to_string());
+
+}
+
+/// Ranks devices that passed the suitability checks: discrete GPUs first,
+/// then by maximum 2D image size as a rough measure of capability. Compared
+/// as a tuple, so no image size can outrank being discrete.
+pub fn score_physical_device(_properties: &vk::PhysicalDeviceProperties) -> (bool, u32) {
+
+    let discrete = _properties.device_type == vk::PhysicalDeviceType::DISCRETE_GPU;
+
+    return (discrete, _properties.limits.max_image_dimension_2d);
+
+}
+
+/// Makes `_device` the one used. `_forced` is set when the user picked it
+/// (--gpu), who then doesn't need warning that it's a software renderer.
+pub fn select_physical_device(_data: &mut AppData, _device: vk::PhysicalDevice, _properties: &vk::PhysicalDeviceProperties, _forced: bool) {
+
+    info!("Selected physical device ('{}').", _properties.device_name);
+    if is_software_device(_properties) && !_forced {
+        warn!(
+            "'{}' is a software (CPU) renderer; performance will be very poor. Check that GPU drivers are installed.",
+            _properties.device_name
+        );
+    }
+    _data.physical_device = _device;
+
+}
+
+/// Logs subgroup size and supported operations, which decide whether
+/// subgroup-optimized compute kernels are viable. Needs Vulkan 1.1 on both the
+/// instance and the device.
+pub unsafe fn log_subgroup_properties(_instance: &Instance, _data: &AppData) {
+
+    let _p_device = _data.physical_device;
+    let properties = _instance.get_physical_device_properties(_p_device);
+    let device_version = Version::from(properties.api_version);
+    let instance_version = _data.api_version.unwrap_or(API_VERSION);
+    if instance_version < Version::new(1, 1, 0) || device_version < Version::new(1, 1, 0) {
+        debug!("Skipping subgroup properties query (requires Vulkan 1.1, instance {}, device {}).", instance_version, device_version);
+        return;
+    }
+
+    let mut subgroup = vk::PhysicalDeviceSubgroupProperties::default();
+    {
+        let mut properties2 = vk::PhysicalDeviceProperties2::builder().push_next(&mut subgroup);
+        _instance.get_physical_device_properties2(_p_device, &mut properties2);
+    }
+
+    info!("Subgroup size: {}.", subgroup.subgroup_size);
+    info!("Subgroup supported stages: {:?}.", subgroup.supported_stages);
+    info!("Subgroup supported operations: {:?}.", subgroup.supported_operations);
+    info!("Subgroup quad operations in all stages: {}.", subgroup.quad_operations_in_all_stages == vk::TRUE);
+
+}
+
+/// Prints every physical device (in `--gpu` order) with the details useful in
+/// a bug report: identity, versions, key limits, queue families and device
+/// extensions.
+pub unsafe fn print_device_report(_instance: &Instance) -> Result<()> {
+
+    let mut devices = _instance.enumerate_physical_devices()?;
+    if devices.is_empty() {
+        println!("No Vulkan devices found.");
+    }
+    devices.sort_by_cached_key(|&d| device_sort_key(&_instance.get_physical_device_properties(d)));
+
+    for (index, &device) in devices.iter().enumerate() {
+        let properties = _instance.get_physical_device_properties(device);
+        let families = _instance.get_physical_device_queue_family_properties(device);
+        let extensions = _instance.enumerate_device_extension_properties(device, None)?;
+        print!("{}", device_report(index, &properties, &families, &extensions));
+    }
+
+    return Ok(());
+
+}
+
+pub fn device_report(
+    _index: usize,
+    _properties: &vk::PhysicalDeviceProperties,
+    _families: &[QueueFamilyProperties],
+    _extensions: &[vk::ExtensionProperties],
+) -> String {
+
+    let limits = &_properties.limits;
+    let mut report = format!("Physical device {}: '{}'\n", _index, _properties.device_name);
+    report += &format!("  Type: {:?}\n", _properties.device_type);
+    report += &format!("  Vendor ID: {:#06x}, device ID: {:#06x}\n", _properties.vendor_id, _properties.device_id);
+    report += &format!("  API version: {}\n", Version::from(_properties.api_version));
+    report += &format!("  Driver version: {}\n", driver_version(_properties.vendor_id, _properties.driver_version));
+    report += &format!(
+        "  Max image dimensions: 1D {}, 2D {}, 3D {}, cube {}, layers {}\n",
+        limits.max_image_dimension_1d,
+        limits.max_image_dimension_2d,
+        limits.max_image_dimension_3d,
+        limits.max_image_dimension_cube,
+        limits.max_image_array_layers,
+    );
+    report += &format!("  Max push constants size: {} bytes\n", limits.max_push_constants_size);
+    report += &format!("  Max bound descriptor sets: {}\n", limits.max_bound_descriptor_sets);
+    report += &format!("  Max sampler anisotropy: {}\n", limits.max_sampler_anisotropy);
+    report += &format!("  Timestamp period: {} ns\n", limits.timestamp_period);
+
+    report += &format!("  Queue families ({}):\n", _families.len());
+    for (index, family) in _families.iter().enumerate() {
+        report += &format!("    {}: {:?}, {} queue(s)\n", index, family.queue_flags, family.queue_count);
+    }
+
+    let mut extensions = _extensions.iter().map(|e| (e.extension_name.to_string(), e.spec_version)).collect::<Vec<_>>();
+    extensions.sort();
+    report += &format!("  Device extensions ({}):\n", extensions.len());
+    for (name, version) in &extensions {
+        report += &format!("    {} (revision {})\n", name, version);
+    }
+
+    return report;
+
+}
+
+/// Formats a driver version, which only follows the Vulkan version encoding
+/// on some vendors. NVIDIA packs it as 10.8.8.6 bits.
+pub fn driver_version(_vendor_id: u32, _version: u32) -> String {
+    const NVIDIA: u32 = 0x10de;
+
+    return match _vendor_id {
+        NVIDIA => format!(
+            "{}.{}.{}.{}",
+            _version >> 22,
+            (_version >> 14) & 0xff,
+            (_version >> 6) & 0xff,
+            _version & 0x3f,
+        ),
+        _ => Version::from(_version).to_string(),
+    };
+}
+
+pub fn is_software_device(_properties: &vk::PhysicalDeviceProperties) -> bool {
+
+    if _properties.device_type == vk::PhysicalDeviceType::CPU {
+        return true;
+    }
+
+    let name = _properties.device_name.to_string().to_lowercase();
+    return SOFTWARE_RENDERERS.iter().any(|r| name.contains(r));
+
+}
+
+pub unsafe fn check_physical_device(_instance: &Instance, _data: &AppData, _p_device: vk::PhysicalDevice) -> Result<()> {
+
+    check_essential_requirements(_instance, _data, _p_device)?;
+
+    if let Some(error) = unmet_optional_requirements(_instance, _data, _p_device).into_iter().next() {
+        return Err(anyhow!(error));
+    }
+
+    return Ok(());
+
+}
+
+/// Requirements a device can never be selected without.
+pub unsafe fn check_essential_requirements(_instance: &Instance, _data: &AppData, _p_device: vk::PhysicalDevice) -> Result<()> {
+
+    QueueFamilyIndices::get(_instance,_data,_p_device)?;
+    check_physical_device_extensions(_instance, _data, _p_device)?;
+    check_physical_device_features(_instance, _data, _p_device)?;
+
+    return Ok(());
+
+}
+
+pub unsafe fn check_physical_device_extensions(_instance: &Instance, _data: &AppData, _p_device: vk::PhysicalDevice) -> Result<()> {
+
+    let available = _instance
+    .enumerate_device_extension_properties(_p_device, None)?
+    .iter()
+    .map(|e| e.extension_name)
+    .collect::<HashSet<_>>();
+
+    if !required_device_extensions(_data).iter().all(|e| available.contains(e)) {
+        return Err(anyhow!(SuitabilityError("Missing required device extensions.")));
+    }
+
+    return Ok(());
+
+}
+
+pub unsafe fn check_physical_device_features(_instance: &Instance, _data: &AppData, _p_device: vk::PhysicalDevice) -> Result<()> {
+
+    let supported = _instance.get_physical_device_features(_p_device);
+    let missing = _data.required_features.missing(&supported);
+    if !missing.is_empty() {
+        return Err(anyhow!("Missing required device feature(s): {}.", missing.join(", ")));
+    }
+
+    return Ok(());
+
+}
+
+/// `DEVICE_EXTENSIONS`, minus the swapchain when rendering headless.
+pub fn required_device_extensions(_data: &AppData) -> &'static [vk::ExtensionName] {
+    return match _data.headless {
+        Some(_) => &[],
+        None => DEVICE_EXTENSIONS,
+    };
+}
+
+/// Requirements that are preferred but may be waived by the fallback pass in
+/// `pick_physical_device`. Returns every one the device fails.
+pub unsafe fn unmet_optional_requirements(_instance: &Instance, _data: &AppData, _p_device: vk::PhysicalDevice) -> Vec<SuitabilityError> {
+
+    let mut unmet = Vec::new();
+
+    let properties = _instance.get_physical_device_properties(_p_device);
+    if properties.device_type != vk::PhysicalDeviceType::DISCRETE_GPU 
+    && properties.device_type != vk::PhysicalDeviceType::INTEGRATED_GPU {
+        unmet.push(SuitabilityError("Only discrete and integrated GPUs are supported."));
+    }
+
+    return unmet;
+
+}
+
+pub fn create_logical_decice(_entry: &Entry, _instance: &Instance, _data: &mut AppData) -> Result<Device> {
+
+    let indices = QueueFamilyIndices::get(_instance, _data, _data.physical_device)?;
+
+    let mut unique_indices = HashSet::new();
+    unique_indices.insert(indices.graphics);
+    unique_indices.insert(indices.present);
+    unique_indices.insert(indices.transfer);
+    unique_indices.extend(indices.compute);
+
+    if indices.transfer == indices.graphics {
+        info!("Using the graphics queue family ({}) for transfers.", indices.transfer);
+    } else {
+        info!("Using dedicated transfer queue family {}.", indices.transfer);
+    }
+
+    let queue_priorities = &[1.0];
+
+    let layers = if _data.validation {
+        vec![VALIDATION_LAYER.as_ptr()]
+    } else {
+        Vec::new()
+    };
+
+    let mut extensions = required_device_extensions(_data)
+    .iter()
+    .map(|e| e.as_ptr())
+    .collect::<Vec<_>>();
+    let mut portability_features = vk::PhysicalDevicePortabilitySubsetFeaturesKHR::default();
+
+    // a device that lists VK_KHR_portability_subset must have it enabled, so
+    // `Off` is only for implementations that list it without needing it
+    let available_extensions = unsafe { _instance.enumerate_device_extension_properties(_data.physical_device, None)? };
+    let subset_available = available_extensions
+    .iter()
+    .any(|e| e.extension_name == vk::KHR_PORTABILITY_SUBSET_EXTENSION.name);
+    if use_portability(_data.portability_mode, _data.portability, subset_available) {
+        extensions.push(vk::KHR_PORTABILITY_SUBSET_EXTENSION.name.as_ptr());
+        unsafe {
+            portability_features = get_portability_subset_features(_instance, _data.physical_device);
+        }
+        _data.portability_subset = Some(portability_features);
+    }
+
+    // shaders declaring Float16 or Int8 need these features enabled, not
+    // just the extension
+    let float16_int8_available = available_extensions
+    .iter()
+    .any(|e| e.extension_name == vk::KHR_SHADER_FLOAT16_INT8_EXTENSION.name);
+    let mut float16_int8_features = match float16_int8_available {
+        true => unsafe { get_float16_int8_features(_instance, _data, _data.physical_device) },
+        false => None,
+    };
+    if float16_int8_features.is_some() {
+        extensions.push(vk::KHR_SHADER_FLOAT16_INT8_EXTENSION.name.as_ptr());
+    }
+    _data.float16_int8_features = float16_int8_features.unwrap_or_default();
+
+    let priority_extension = match _data.queue_priority {
+        Some(_) => unsafe { global_priority_extension(_instance, _data.physical_device)? },
+        None => None,
+    };
+    if _data.queue_priority.is_some() && priority_extension.is_none() {
+        warn!("The device doesn't support queue global priorities; using the default priority.");
+        _data.queue_priority = None;
+    }
+
+    let supported_features = unsafe { _instance.get_physical_device_features(_data.physical_device) };
+    let features = _data.required_features.enabled(&PREFERRED_FEATURES, &supported_features);
+    _data.enabled_features = features;
+
+    let limits = unsafe { _instance.get_physical_device_properties(_data.physical_device).limits };
+    _data.max_sampler_anisotropy = max_sampler_anisotropy(&_data.enabled_features, &limits);
+    match _data.max_sampler_anisotropy {
+        Some(max) => info!("Anisotropic filtering available up to {}x.", max),
+        None => info!("Anisotropic filtering not supported; samplers will not use it."),
+    }
+    if limits.max_push_constants_size < PUSH_CONSTANTS_SIZE {
+        warn!(
+            "The device supports {} bytes of push constants, fewer than the {} the pipeline uses.",
+            limits.max_push_constants_size,
+            PUSH_CONSTANTS_SIZE,
+        );
+    }
+    let usable_samples = usable_msaa_samples(&limits);
+    _data.msaa_samples = choose_msaa_samples(_data.msaa_request, usable_samples);
+    info!("Using {}x MSAA ({}x requested, supported {:?}).", _data.msaa_samples.bits(), _data.msaa_request, usable_samples);
+    let portability = _data.portability_subset.is_some();
+
+    let mut try_create = |_priority: Option<vk::QueueGlobalPriorityKHR>| {
+        let mut extensions = extensions.clone();
+        if let (Some(_), Some(name)) = (_priority, &priority_extension) {
+            extensions.push(name.as_ptr());
+        }
+
+        let mut priority_infos = unique_indices
+        .iter()
+        .map(|_| {
+            vk::DeviceQueueGlobalPriorityCreateInfoKHR::builder()
+            .global_priority(_priority.unwrap_or_default())
+        })
+        .collect::<Vec<_>>();
+
+        let queue_infos = unique_indices
+        .iter()
+        .zip(priority_infos.iter_mut())
+        .map(|(i, priority_info)| {
+            let info = vk::DeviceQueueCreateInfo::builder()
+            .queue_family_index(*i)
+            .queue_priorities(queue_priorities);
+            if _priority.is_some() {
+                return info.push_next(priority_info);
+            }
+            return info;
+        })
+        .collect::<Vec<_>>();
+
+        let mut info = vk::DeviceCreateInfo::builder()
+        .queue_create_infos(&queue_infos)
+        .enabled_layer_names(&layers)
+        .enabled_extension_names(&extensions)
+        .enabled_features(&features);
+
+        // enable exactly the subset features the implementation reports
+        if portability {
+            info = info.push_next(&mut portability_features);
+        }
+        if let Some(features) = &mut float16_int8_features {
+            info = info.push_next(features);
+        }
+
+        let device = unsafe { _instance.create_device(_data.physical_device, &info, None) };
+        return device.map(|d| (d, extensions));
+    };
+
+    // drivers may refuse elevated priorities (e.g. REALTIME for unprivileged
+    // processes); that's not worth failing over
+    let (device, extensions) = match try_create(_data.queue_priority) {
+        Err(vk::ErrorCode::NOT_PERMITTED_KHR) if _data.queue_priority.is_some() => {
+            info!("Not permitted to use queue global priority {:?}; using the default priority.", _data.queue_priority.unwrap());
+            _data.queue_priority = None;
+            try_create(None)?
+        }
+        result => result?,
+    };
+    track_created("Device");
+
+    _data.device_extensions = names_from_ptrs(&extensions);
+    debug!("Enabled device extensions: {:?}", _data.device_extensions);
+
+    unsafe {
+        _data.graphics_queue = device.get_device_queue(indices.graphics, 0);
+        _data.present_queue = device.get_device_queue(indices.present, 0);
+        _data.transfer_queue = device.get_device_queue(indices.transfer, 0);
+        if let Some(compute) = indices.compute {
+            _data.compute_queue = device.get_device_queue(compute, 0);
+        }
+    }
+
+    _data.buffer_queue_families = vec![indices.graphics];
+    for family in std::iter::once(indices.transfer).chain(indices.compute) {
+        if !_data.buffer_queue_families.contains(&family) {
+            _data.buffer_queue_families.push(family);
+        }
+    }
+
+    return Ok(device);
+
+}
+
+pub fn max_sampler_anisotropy(_features: &vk::PhysicalDeviceFeatures, _limits: &vk::PhysicalDeviceLimits) -> Option<f32> {
+    return (_features.sampler_anisotropy == vk::TRUE).then_some(_limits.max_sampler_anisotropy);
+}
+
+/// Sample counts usable for color attachments and, once there is one, a depth
+/// attachment of the same render pass.
+pub fn usable_msaa_samples(_limits: &vk::PhysicalDeviceLimits) -> vk::SampleCountFlags {
+    return _limits.framebuffer_color_sample_counts & _limits.framebuffer_depth_sample_counts;
+}
+
+/// The highest usable sample count not above `_requested`; one sample is
+/// always supported.
+pub fn choose_msaa_samples(_requested: u32, _usable: vk::SampleCountFlags) -> vk::SampleCountFlags {
+    return (0..7)
+    .rev()
+    .map(|shift| vk::SampleCountFlags::from_bits_truncate(1 << shift))
+    .find(|&samples| samples.bits() <= _requested && _usable.contains(samples))
+    .unwrap_or(vk::SampleCountFlags::_1);
+}
+
+/// `anisotropy_enable` and `max_anisotropy` for sampler creation: the
+/// device's maximum when anisotropy was enabled, otherwise off.
+pub fn sampler_anisotropy(_data: &AppData) -> (bool, f32) {
+    return match _data.max_sampler_anisotropy {
+        Some(max) => (true, max),
+        None => (false, 1.0),
+    };
+}
+
+/// Returns the global priority extension the device supports, preferring the
+/// KHR promotion over the original EXT.
+pub unsafe fn global_priority_extension(_instance: &Instance, _p_device: vk::PhysicalDevice) -> Result<Option<vk::ExtensionName>> {
+
+    let available = _instance
+    .enumerate_device_extension_properties(_p_device, None)?
+    .iter()
+    .map(|e| e.extension_name)
+    .collect::<HashSet<_>>();
+
+    return Ok([vk::KHR_GLOBAL_PRIORITY_EXTENSION.name, vk::EXT_GLOBAL_PRIORITY_EXTENSION.name]
+    .into_iter()
+    .find(|name| available.contains(name)));
+
+}
+
+pub fn parse_queue_priority(_level: &str) -> Result<vk::QueueGlobalPriorityKHR> {
+    return match _level.to_ascii_lowercase().as_str() {
+        "low" => Ok(vk::QueueGlobalPriorityKHR::LOW),
+        "medium" => Ok(vk::QueueGlobalPriorityKHR::MEDIUM),
+        "high" => Ok(vk::QueueGlobalPriorityKHR::HIGH),
+        "realtime" => Ok(vk::QueueGlobalPriorityKHR::REALTIME),
+        _ => Err(anyhow!("Unknown queue priority '{}', expected low, medium, high or realtime.", _level)),
+    };
+}
+
+/// Queries `shaderFloat16` and `shaderInt8` on a device with
+/// `VK_KHR_shader_float16_int8`, or `None` when neither is supported or the
+/// instance (below Vulkan 1.1) can't ask.
+pub unsafe fn get_float16_int8_features(
+    _instance: &Instance,
+    _data: &AppData,
+    _p_device: vk::PhysicalDevice,
+) -> Option<vk::PhysicalDeviceShaderFloat16Int8Features> {
+
+    if _data.api_version.unwrap_or(API_VERSION) < Version::new(1, 1, 0) {
+        debug!("Not querying shaderFloat16/shaderInt8 (requires Vulkan 1.1).");
+        return None;
+    }
+
+    let mut float16_int8 = vk::PhysicalDeviceShaderFloat16Int8Features::default();
+    {
+        let mut features = vk::PhysicalDeviceFeatures2::builder().push_next(&mut float16_int8);
+        _instance.get_physical_device_features2(_p_device, &mut features);
+    }
+    float16_int8.next = std::ptr::null_mut();
+    debug!("shaderFloat16: {}, shaderInt8: {}.", float16_int8.shader_float16 == vk::TRUE, float16_int8.shader_int8 == vk::TRUE);
+
+    if float16_int8.shader_float16 != vk::TRUE && float16_int8.shader_int8 != vk::TRUE {
+        return None;
+    }
+
+    return Some(float16_int8);
+
+}
+
+/// Queries which features a `VK_KHR_portability_subset` implementation (e.g.
+/// MoltenVK) supports, logging the ones it lacks so validation errors from
+/// using them aren't a surprise.
+pub unsafe fn get_portability_subset_features(_instance: &Instance, _p_device: vk::PhysicalDevice) -> vk::PhysicalDevicePortabilitySubsetFeaturesKHR {
+
+    let mut subset = vk::PhysicalDevicePortabilitySubsetFeaturesKHR::default();
+    {
+        let mut features = vk::PhysicalDeviceFeatures2::builder().push_next(&mut subset);
+        _instance.get_physical_device_features2_khr(_p_device, &mut features);
+    }
+    subset.next = std::ptr::null_mut();
+
+    let supported = [
+        ("constantAlphaColorBlendFactors", subset.constant_alpha_color_blend_factors),
+        ("events", subset.events),
+        ("imageViewFormatReinterpretation", subset.image_view_format_reinterpretation),
+        ("imageViewFormatSwizzle", subset.image_view_format_swizzle),
+        ("imageView2DOn3DImage", subset.image_view_2d_on_3d_image),
+        ("multisampleArrayImage", subset.multisample_array_image),
+        ("mutableComparisonSamplers", subset.mutable_comparison_samplers),
+        ("pointPolygons", subset.point_polygons),
+        ("samplerMipLodBias", subset.sampler_mip_lod_bias),
+        ("separateStencilMaskRef", subset.separate_stencil_mask_ref),
+        ("shaderSampleRateInterpolationFunctions", subset.shader_sample_rate_interpolation_functions),
+        ("tessellationIsolines", subset.tessellation_isolines),
+        ("tessellationPointMode", subset.tessellation_point_mode),
+        ("triangleFans", subset.triangle_fans),
+        ("vertexAttributeAccessBeyondStride", subset.vertex_attribute_access_beyond_stride),
+    ];
+
+    for (name, value) in supported {
+        if value != vk::TRUE {
+            warn!("Portability subset feature '{}' is not supported.", name);
+        }
+    }
+
+    return subset;
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::tests::test_instance;
+
+    fn properties(name: &str, device_type: vk::PhysicalDeviceType) -> vk::PhysicalDeviceProperties {
+        return vk::PhysicalDeviceProperties::builder()
+        .device_name(vk::StringArray::from_bytes(name.as_bytes()))
+        .device_type(device_type)
+        .build();
+    }
+
+    #[test]
+    fn software_devices_are_detected() {
+        assert!(is_software_device(&properties("llvmpipe (LLVM 17.0.6, 256 bits)", vk::PhysicalDeviceType::CPU)));
+        assert!(is_software_device(&properties("SwiftShader Device", vk::PhysicalDeviceType::INTEGRATED_GPU)));
+        assert!(!is_software_device(&properties("NVIDIA GeForce RTX 3080", vk::PhysicalDeviceType::DISCRETE_GPU)));
+    }
+
+    #[test]
+    fn devices_sort_by_type_then_ids() {
+        let mut devices = [
+            properties("llvmpipe", vk::PhysicalDeviceType::CPU),
+            properties("Intel UHD", vk::PhysicalDeviceType::INTEGRATED_GPU),
+            properties("GeForce", vk::PhysicalDeviceType::DISCRETE_GPU),
+        ];
+        devices.sort_by_key(device_sort_key);
+
+        let names = devices.iter().map(|d| d.device_name.to_string()).collect::<Vec<_>>();
+        assert_eq!(names, ["GeForce", "Intel UHD", "llvmpipe"]);
+    }
+
+    #[test]
+    fn queue_priority_levels_parse() {
+        assert_eq!(parse_queue_priority("high").unwrap(), vk::QueueGlobalPriorityKHR::HIGH);
+        assert_eq!(parse_queue_priority("REALTIME").unwrap(), vk::QueueGlobalPriorityKHR::REALTIME);
+        assert!(parse_queue_priority("urgent").is_err());
+    }
+
+    #[test]
+    fn discrete_devices_outscore_integrated() {
+        let mut discrete = properties("discrete", vk::PhysicalDeviceType::DISCRETE_GPU);
+        discrete.limits.max_image_dimension_2d = 8192;
+        let mut integrated = properties("integrated", vk::PhysicalDeviceType::INTEGRATED_GPU);
+        integrated.limits.max_image_dimension_2d = 8192;
+        assert!(score_physical_device(&discrete) > score_physical_device(&integrated));
+
+        // a larger image limit doesn't make up for being integrated
+        integrated.limits.max_image_dimension_2d = 16384;
+        assert!(score_physical_device(&discrete) > score_physical_device(&integrated));
+
+        let mut larger = properties("larger", vk::PhysicalDeviceType::DISCRETE_GPU);
+        larger.limits.max_image_dimension_2d = 16384;
+        assert!(score_physical_device(&larger) > score_physical_device(&discrete));
+    }
+
+    #[test]
+    fn transfer_family_must_not_be_graphics() {
+        let family = |flags| vk::QueueFamilyProperties { queue_flags: flags, ..Default::default() };
+
+        let families = [family(vk::QueueFlags::GRAPHICS | vk::QueueFlags::TRANSFER), family(vk::QueueFlags::TRANSFER)];
+        assert_eq!(find_dedicated_transfer_family(&families), Some(1));
+
+        let families = [family(vk::QueueFlags::GRAPHICS | vk::QueueFlags::TRANSFER)];
+        assert_eq!(find_dedicated_transfer_family(&families), None);
+    }
+
+    #[test]
+    fn compute_family_prefers_compute_only() {
+        let family = |flags| vk::QueueFamilyProperties { queue_flags: flags, ..Default::default() };
+
+        let families = [family(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE), family(vk::QueueFlags::COMPUTE)];
+        assert_eq!(find_compute_family(&families), Some(1));
+
+        let families = [family(vk::QueueFlags::TRANSFER), family(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)];
+        assert_eq!(find_compute_family(&families), Some(1));
+
+        assert_eq!(find_compute_family(&[family(vk::QueueFlags::GRAPHICS)]), None);
+    }
+
+    #[test]
+    fn queue_families_need_graphics_and_present() {
+        let family = |flags| vk::QueueFamilyProperties { queue_flags: flags, ..Default::default() };
+
+        let families = [family(vk::QueueFlags::TRANSFER), family(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)];
+        let indices = QueueFamilyIndices::from_families(&families, Some(1)).unwrap();
+        assert_eq!((indices.graphics, indices.present, indices.transfer, indices.compute), (1, 1, 0, Some(1)));
+
+        for (families, present) in [(&families[..1], Some(0)), (&families[..], None)] {
+            let error = QueueFamilyIndices::from_families(families, present).err().unwrap();
+            assert!(error.downcast_ref::<SuitabilityError>().is_some());
+        }
+    }
+
+    #[test]
+    fn real_devices_get_queue_families_and_requirements() {
+        let Some(instance) = (unsafe { test_instance() }) else {
+            eprintln!("No Vulkan loader available, skipping.");
+            return;
+        };
+
+        unsafe {
+            // no surface: headless rendering presents nothing
+            let required_features = RequiredFeatures { geometry_shader: true, ..Default::default() };
+            let data = AppData { required_features, ..Default::default() };
+            let devices = instance.enumerate_physical_devices().unwrap_or_default();
+
+            let mut with_graphics = 0;
+            for &device in &devices {
+                let families = instance.get_physical_device_queue_family_properties(device);
+                match QueueFamilyIndices::get(&instance, &data, device) {
+                    Ok(indices) => {
+                        with_graphics += 1;
+                        assert_eq!(Some(indices.graphics), find_graphics_family(&families));
+                        assert_eq!(indices.present, indices.graphics);
+                        assert!(families[indices.transfer as usize].queue_flags.contains(vk::QueueFlags::TRANSFER)
+                            || indices.transfer == indices.graphics);
+                    }
+                    Err(error) => {
+                        assert_eq!(find_graphics_family(&families), None);
+                        assert!(error.downcast_ref::<SuitabilityError>().is_some());
+                    }
+                }
+
+                let features = instance.get_physical_device_features(device);
+                let missing_geometry = check_physical_device_features(&instance, &data, device)
+                .is_err_and(|e| e.to_string().contains("geometryShader"));
+                assert_eq!(missing_geometry, features.geometry_shader != vk::TRUE);
+            }
+
+            if !devices.is_empty() {
+                assert!(with_graphics > 0, "No device has a graphics queue family.");
+            }
+            instance.destroy_instance(None);
+        }
+    }
+
+    #[test]
+    fn required_features_are_named_and_always_enabled() {
+        let supported = vk::PhysicalDeviceFeatures { sampler_anisotropy: vk::TRUE, fill_mode_non_solid: vk::TRUE, ..Default::default() };
+
+        let required = RequiredFeatures { fill_mode_non_solid: true, geometry_shader: true, wide_lines: true, ..Default::default() };
+        assert_eq!(required.missing(&supported), ["geometryShader", "wideLines"]);
+        assert!(RequiredFeatures::default().missing(&vk::PhysicalDeviceFeatures::default()).is_empty());
+
+        // preferred features only when supported, required ones regardless
+        let enabled = RequiredFeatures { wide_lines: true, ..Default::default() }.enabled(&PREFERRED_FEATURES, &supported);
+        assert_eq!(enabled.sampler_anisotropy, vk::TRUE);
+        assert_eq!(enabled.fill_mode_non_solid, vk::TRUE);
+        assert_eq!(enabled.geometry_shader, vk::FALSE);
+        assert_eq!(enabled.wide_lines, vk::TRUE);
+        assert_eq!(enabled.tessellation_shader, vk::FALSE);
+    }
+
+    #[test]
+    fn anisotropy_follows_the_enabled_feature() {
+        let limits = vk::PhysicalDeviceLimits { max_sampler_anisotropy: 16.0, ..Default::default() };
+        let features = vk::PhysicalDeviceFeatures { sampler_anisotropy: vk::TRUE, ..Default::default() };
+        assert_eq!(max_sampler_anisotropy(&features, &limits), Some(16.0));
+        assert_eq!(max_sampler_anisotropy(&vk::PhysicalDeviceFeatures::default(), &limits), None);
+
+        let data = AppData { max_sampler_anisotropy: Some(8.0), ..Default::default() };
+        assert_eq!(sampler_anisotropy(&data), (true, 8.0));
+        assert_eq!(sampler_anisotropy(&AppData::default()), (false, 1.0));
+    }
+
+    #[test]
+    fn device_report_lists_limits_families_and_extensions() {
+        let mut device = properties("GeForce", vk::PhysicalDeviceType::DISCRETE_GPU);
+        device.vendor_id = 0x10de;
+        device.api_version = Version::new(1, 3, 0).into();
+        device.driver_version = (535 << 22) | (104 << 14) | (5 << 6);
+        device.limits.max_push_constants_size = 256;
+        let families = [vk::QueueFamilyProperties { queue_flags: vk::QueueFlags::GRAPHICS, queue_count: 16, ..Default::default() }];
+        let extension = |name: &str| vk::ExtensionProperties {
+            extension_name: vk::StringArray::from_bytes(name.as_bytes()),
+            spec_version: 1
+        };
+        let extensions = [extension("VK_KHR_swapchain"), extension("VK_EXT_memory_budget")];
+
+        let report = device_report(0, &device, &families, &extensions);
+        assert!(report.starts_with("Physical device 0: 'GeForce'\n"));
+        assert!(report.contains("  API version: 1.3.0\n"));
+        assert!(report.contains("  Driver version: 535.104.5.0\n"));
+        assert!(report.contains("  Max push constants size: 256 bytes\n"));
+        assert!(report.contains("    0: GRAPHICS, 16 queue(s)\n"));
+        // sorted by name
+        assert!(report.ends_with("    VK_EXT_memory_budget (revision 1)\n    VK_KHR_swapchain (revision 1)\n"));
+
+        assert_eq!(driver_version(0x1002, Version::new(2, 0, 279).into()), "2.0.279");
+    }
+
+    #[test]
+    fn unsuitable_devices_are_listed_with_reasons() {
+        let rejected = [
+            ("llvmpipe".to_string(), "Missing swapchain support.".to_string()),
+            ("GPU".to_string(), "Missing device extension VK_KHR_swapchain.".to_string()),
+        ];
+        assert_eq!(
+            no_suitable_device_message(&rejected),
+            "None of the 2 Vulkan device(s) is suitable ('llvmpipe': Missing swapchain support.; 'GPU': Missing device extension VK_KHR_swapchain.)."
+        );
+    }
+
+    #[test]
+    fn msaa_samples_clamp_to_color_and_depth_support() {
+        use vk::SampleCountFlags as S;
+        let limits = vk::PhysicalDeviceLimits {
+            framebuffer_color_sample_counts: S::_1 | S::_2 | S::_4 | S::_8,
+            framebuffer_depth_sample_counts: S::_1 | S::_2 | S::_4,
+            ..Default::default()
+        };
+        let usable = usable_msaa_samples(&limits);
+        assert_eq!(usable, S::_1 | S::_2 | S::_4);
+
+        assert_eq!(choose_msaa_samples(8, usable), S::_4);
+        assert_eq!(choose_msaa_samples(2, usable), S::_2);
+        assert_eq!(choose_msaa_samples(1, usable), S::_1);
+        assert_eq!(choose_msaa_samples(4, S::_1 | S::_8), S::_1);
+    }
+}
@@ -0,0 +1,51 @@
+//! Debug-build bookkeeping of live Vulkan handles.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Live Vulkan handles per object type, maintained in debug builds (and in
+/// tests, release ones too) so teardown leaks show up at exit. Every
+/// create/destroy pair should call `track_created`/`track_destroyed` with the
+/// same type name.
+pub static LIVE_HANDLES: Mutex<BTreeMap<&'static str, i64>> = Mutex::new(BTreeMap::new());
+
+pub fn track_created(_kind: &'static str) {
+    if cfg!(any(debug_assertions, test)) {
+        *LIVE_HANDLES.lock().unwrap().entry(_kind).or_insert(0) += 1;
+    }
+}
+
+pub fn track_destroyed(_kind: &'static str) {
+    if cfg!(any(debug_assertions, test)) {
+        *LIVE_HANDLES.lock().unwrap().entry(_kind).or_insert(0) -= 1;
+    }
+}
+
+/// Returns every object type whose create/destroy count isn't balanced.
+pub fn leaked_handles() -> Vec<(&'static str, i64)> {
+    return LIVE_HANDLES
+    .lock()
+    .unwrap()
+    .iter()
+    .filter(|(_, &count)| count != 0)
+    .map(|(&kind, &count)| (kind, count))
+    .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_tracking_reports_unbalanced_types() {
+        track_created("TestLeaked");
+        track_created("TestBalanced");
+        track_destroyed("TestBalanced");
+
+        let leaks = leaked_handles();
+        assert!(leaks.contains(&("TestLeaked", 1)));
+        assert!(!leaks.iter().any(|(kind, _)| *kind == "TestBalanced"));
+
+        track_destroyed("TestLeaked");
+    }
+}
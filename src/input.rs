@@ -0,0 +1,86 @@
+//! Keyboard state sampled once per event loop iteration.
+
+use winit::keyboard::KeyCode;
+
+use std::collections::HashSet;
+
+/// Keys held down, plus the ones that went down or up since the last
+/// `end_frame`, so one-shot actions fire once however long a key is held.
+#[derive(Clone, Debug, Default)]
+pub struct InputState {
+    pub held: HashSet<KeyCode>,
+    pub pressed: HashSet<KeyCode>,
+    pub released: HashSet<KeyCode>
+}
+
+impl InputState {
+    /// Records a key event. Auto-repeated presses of a held key aren't new
+    /// presses.
+    pub fn key(&mut self, code: KeyCode, pressed: bool) {
+        if pressed {
+            if self.held.insert(code) {
+                self.pressed.insert(code);
+            }
+        } else if self.held.remove(&code) {
+            self.released.insert(code);
+        }
+    }
+
+    pub fn is_held(&self, code: KeyCode) -> bool {
+        return self.held.contains(&code);
+    }
+
+    /// Whether `code` went down since the last `end_frame`, even if it was
+    /// released again in between.
+    pub fn just_pressed(&self, code: KeyCode) -> bool {
+        return self.pressed.contains(&code);
+    }
+
+    pub fn just_released(&self, code: KeyCode) -> bool {
+        return self.released.contains(&code);
+    }
+
+    /// Forgets this iteration's presses and releases; held keys stay.
+    pub fn end_frame(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+    }
+
+    /// Releases everything, for when the window loses focus and would miss
+    /// the key-up events.
+    pub fn clear(&mut self) {
+        self.released.extend(self.held.drain());
+        self.pressed.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presses_fire_once_until_released() {
+        let mut input = InputState::default();
+        input.key(KeyCode::F11, true);
+        assert!(input.just_pressed(KeyCode::F11) && input.is_held(KeyCode::F11));
+
+        // held across iterations, with the OS repeating the key
+        input.end_frame();
+        input.key(KeyCode::F11, true);
+        assert!(!input.just_pressed(KeyCode::F11) && input.is_held(KeyCode::F11));
+
+        input.key(KeyCode::F11, false);
+        assert!(input.just_released(KeyCode::F11) && !input.is_held(KeyCode::F11));
+
+        // a tap within one iteration still counts
+        input.end_frame();
+        input.key(KeyCode::Tab, true);
+        input.key(KeyCode::Tab, false);
+        assert!(input.just_pressed(KeyCode::Tab) && !input.is_held(KeyCode::Tab));
+
+        input.end_frame();
+        input.key(KeyCode::KeyW, true);
+        input.clear();
+        assert!(input.held.is_empty() && input.just_released(KeyCode::KeyW));
+    }
+}
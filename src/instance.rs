@@ -0,0 +1,534 @@
+//! Instance creation and validation message routing.
+
+use anyhow::{anyhow, Result};
+
+use log::*;
+
+use winit::window::Window;
+
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::Version;
+#[allow(deprecated)]
+use vulkanalia::vk::ExtDebugReportExtension;
+use vulkanalia::vk::ExtDebugUtilsExtension;
+use vulkanalia::vk::KhrSurfaceExtension;
+use vulkanalia::window as vk_window;
+
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use crate::app::AppData;
+use crate::config::AppIdentity;
+use crate::handles::{track_created, track_destroyed};
+
+pub const PORTABILITY_MACOS_VERSION: Version = Version::new(1,3,216);
+// lowest Vulkan API version we run on, which every loader accepts
+pub const API_VERSION: Version = Version::new(1, 0, 0);
+// highest Vulkan API version requested in ApplicationInfo
+pub const TARGET_API_VERSION: Version = Version::new(1, 3, 0);
+// validation default when neither --validation nor VULCAN_VALIDATION is set
+pub const VALIDATION_DEFAULT: bool = cfg!(debug_assertions);
+pub const VALIDATION_LAYER: vk::ExtensionName = vk::ExtensionName::from_bytes(b"VK_LAYER_KHRONOS_validation");
+
+/// Names `_handle` in validation messages through VK_EXT_debug_utils. A
+/// no-op unless validation is on and the utils messenger was created.
+pub unsafe fn set_debug_name<T: vk::Handle>(_instance: &Instance, _device: &Device, _data: &AppData, _handle: T, _name: &str)
+where
+    u64: TryFrom<T::Repr>,
+{
+    if _data.messenger.is_null() || _handle.is_null() {
+        return;
+    }
+    let (Ok(handle), Ok(name)) = (u64::try_from(_handle.as_raw()), CString::new(_name)) else {
+        return;
+    };
+
+    let info = vk::DebugUtilsObjectNameInfoEXT::builder()
+    .object_type(T::TYPE)
+    .object_handle(handle)
+    .object_name(name.as_bytes_with_nul());
+
+    if let Err(error) = _instance.set_debug_utils_object_name_ext(_device.handle(), &info) {
+        debug!("Failed to name {:?} {:?}: {}", T::TYPE, _name, error);
+    }
+}
+
+/// Destroys the instance and the instance-level objects (debug messengers,
+/// surface) that were created so far.
+pub unsafe fn destroy_instance_objects(_instance: &Instance, _data: &mut AppData) {
+
+    if !_data.surface.is_null() {
+        _instance.destroy_surface_khr(_data.surface, None);
+        track_destroyed("SurfaceKHR");
+    }
+
+    if !_data.messenger.is_null() {
+        _instance.destroy_debug_utils_messenger_ext(_data.messenger, None);
+        track_destroyed("DebugUtilsMessengerEXT");
+    }
+
+    #[allow(deprecated)]
+    if !_data.debug_report_callback.is_null() {
+        _instance.destroy_debug_report_callback_ext(_data.debug_report_callback, None);
+        track_destroyed("DebugReportCallbackEXT");
+    }
+
+    _instance.destroy_instance(None);
+    track_destroyed("Instance");
+
+}
+
+/// Converts the NUL-terminated name pointers handed to Vulkan back into strings.
+pub fn names_from_ptrs(_names: &[*const c_char]) -> Vec<String> {
+    return _names
+    .iter()
+    .map(|&n| unsafe { CStr::from_ptr(n) }.to_string_lossy().into_owned())
+    .collect();
+}
+
+pub extern "system" fn debug_callback(severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    type_: vk::DebugUtilsMessageTypeFlagsEXT,
+    data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    _: *mut c_void,
+) -> vk::Bool32 {
+    let data = unsafe { *data };
+    let message = unsafe { CStr::from_ptr(data.message) }.to_string_lossy();
+    
+    if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::ERROR {
+        error!("({:?}) {}", type_, message);
+    } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING {
+        warn!("({:?}) {}", type_, message);
+    } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::INFO {
+        info!("({:?}) {}", type_, message);
+    } else {
+        trace!("({:?}) {}", type_, message);
+    }
+    write_validation_log(&format!("{:?}", severity), &format!("{:?}", type_), &message);
+
+    return vk::FALSE;
+}
+
+pub extern "system" fn debug_report_callback(flags: vk::DebugReportFlagsEXT,
+    _: vk::DebugReportObjectTypeEXT,
+    _: u64,
+    _: usize,
+    _: i32,
+    layer_prefix: *const c_char,
+    message: *const c_char,
+    _: *mut c_void,
+) -> vk::Bool32 {
+    let prefix = if layer_prefix.is_null() {
+        "".into()
+    } else {
+        unsafe { CStr::from_ptr(layer_prefix) }.to_string_lossy()
+    };
+    let message = unsafe { CStr::from_ptr(message) }.to_string_lossy();
+
+    if flags.contains(vk::DebugReportFlagsEXT::ERROR) {
+        error!("({}) {}", prefix, message);
+    } else if flags.intersects(vk::DebugReportFlagsEXT::WARNING | vk::DebugReportFlagsEXT::PERFORMANCE_WARNING) {
+        warn!("({}) {}", prefix, message);
+    } else if flags.contains(vk::DebugReportFlagsEXT::INFORMATION) {
+        info!("({}) {}", prefix, message);
+    } else {
+        trace!("({}) {}", prefix, message);
+    }
+    write_validation_log(&format!("{:?}", flags), &prefix, &message);
+
+    return vk::FALSE;
+}
+
+/// File named by `VULCAN_VALIDATION_LOG` that validation messages are also
+/// appended to. The debug callbacks can't capture state, hence the global.
+pub static VALIDATION_LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Opens the validation log if `VULCAN_VALIDATION_LOG` is set. Failing to
+/// open it only costs the file copy of the messages.
+pub fn open_validation_log() {
+    let Some(path) = std::env::var_os("VULCAN_VALIDATION_LOG") else {
+        return;
+    };
+
+    match std::fs::OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(file) => {
+            info!("Appending validation messages to {}.", Path::new(&path).display());
+            let _ = VALIDATION_LOG.set(Mutex::new(file));
+        }
+        Err(error) => warn!("Failed to open validation log {}: {}", Path::new(&path).display(), error),
+    }
+}
+
+pub fn format_validation_line(_timestamp: Duration, _severity: &str, _types: &str, _message: &str) -> String {
+    return format!("[{}.{:03}] {} ({}) {}", _timestamp.as_secs(), _timestamp.subsec_millis(), _severity, _types, _message);
+}
+
+/// Appends a message to the validation log, if one is open. Called from the
+/// debug callbacks, so errors are ignored rather than unwound across FFI.
+pub fn write_validation_log(_severity: &str, _types: &str, _message: &str) {
+    let Some(file) = VALIDATION_LOG.get() else {
+        return;
+    };
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    if let Ok(mut file) = file.lock() {
+        let _ = writeln!(file, "{}", format_validation_line(timestamp, _severity, _types, _message));
+    }
+}
+
+/// Which extension routes validation messages to `log`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugExtension {
+    Utils,
+    // deprecated, only used when the loader predates VK_EXT_debug_utils
+    Report
+}
+
+/// Reads the minimum debug message severity from `VULCAN_LOG_SEVERITY`
+/// (`verbose`, `info`, `warning` or `error`); everything is forwarded when
+/// it's unset. Filtering happens in the messenger, so suppressed messages
+/// never reach the callback.
+pub fn debug_severity_from_env() -> Result<vk::DebugUtilsMessageSeverityFlagsEXT> {
+    return match std::env::var("VULCAN_LOG_SEVERITY") {
+        Ok(level) => parse_debug_severity(&level),
+        Err(_) => Ok(vk::DebugUtilsMessageSeverityFlagsEXT::all()),
+    };
+}
+
+/// Whether to use the portability path (`VK_KHR_portability_enumeration` on
+/// the instance, `VK_KHR_portability_subset` on the device), from
+/// `VULCAN_PORTABILITY`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PortabilityMode {
+    // macOS with a new enough loader, or after ERROR_INCOMPATIBLE_DRIVER
+    #[default]
+    Auto,
+    // whenever the extension is available, e.g. behind a translation layer
+    Force,
+    Off
+}
+
+impl PortabilityMode {
+    pub fn parse(_mode: &str) -> Result<Self> {
+        return match _mode.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "force" => Ok(Self::Force),
+            "off" => Ok(Self::Off),
+            _ => Err(anyhow!("VULCAN_PORTABILITY must be force, off or auto, not {:?}.", _mode)),
+        };
+    }
+
+    pub fn from_env() -> Result<Self> {
+        return match std::env::var("VULCAN_PORTABILITY") {
+            Ok(mode) => Self::parse(&mode),
+            Err(_) => Ok(Self::Auto),
+        };
+    }
+}
+
+/// Decides on a portability extension for the instance or device: `_auto` is
+/// what `Auto` would pick, and `_available` whether the extension is listed.
+pub fn use_portability(_mode: PortabilityMode, _auto: bool, _available: bool) -> bool {
+    return match _mode {
+        PortabilityMode::Auto => _auto && _available,
+        PortabilityMode::Force => _available,
+        PortabilityMode::Off => false,
+    };
+}
+
+/// Returns the given severity and every one above it.
+pub fn parse_debug_severity(_level: &str) -> Result<vk::DebugUtilsMessageSeverityFlagsEXT> {
+
+    let error = vk::DebugUtilsMessageSeverityFlagsEXT::ERROR;
+    let warning = error | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING;
+    let info = warning | vk::DebugUtilsMessageSeverityFlagsEXT::INFO;
+    let verbose = info | vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE;
+
+    return match _level.to_ascii_lowercase().as_str() {
+        "verbose" => Ok(verbose),
+        "info" => Ok(info),
+        "warning" => Ok(warning),
+        "error" => Ok(error),
+        _ => Err(anyhow!("VULCAN_LOG_SEVERITY must be verbose, info, warning or error, not {:?}.", _level)),
+    };
+
+}
+
+/// Maps a debug utils severity mask to the closest VK_EXT_debug_report flags.
+#[allow(deprecated)]
+pub fn debug_report_flags(_severity: vk::DebugUtilsMessageSeverityFlagsEXT) -> vk::DebugReportFlagsEXT {
+
+    let mut flags = vk::DebugReportFlagsEXT::empty();
+    if _severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        flags |= vk::DebugReportFlagsEXT::ERROR;
+    }
+    if _severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+        flags |= vk::DebugReportFlagsEXT::WARNING | vk::DebugReportFlagsEXT::PERFORMANCE_WARNING;
+    }
+    if _severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+        flags |= vk::DebugReportFlagsEXT::INFORMATION;
+    }
+    if _severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE) {
+        flags |= vk::DebugReportFlagsEXT::DEBUG;
+    }
+
+    return flags;
+
+}
+
+#[allow(deprecated)]
+pub fn choose_debug_extension(_available: &HashSet<vk::ExtensionName>) -> Option<DebugExtension> {
+    if _available.contains(&vk::EXT_DEBUG_UTILS_EXTENSION.name) {
+        return Some(DebugExtension::Utils);
+    } else if _available.contains(&vk::EXT_DEBUG_REPORT_EXTENSION.name) {
+        return Some(DebugExtension::Report);
+    } else {
+        return None;
+    }
+}
+
+/// The API version to request from a loader supporting `_instance_version`:
+/// its major and minor version, capped at `TARGET_API_VERSION`. A 1.0 loader
+/// fails instance creation for anything newer than 1.0.
+pub fn negotiate_api_version(_instance_version: Version) -> Version {
+    let supported = Version::new(_instance_version.major, _instance_version.minor, 0);
+    return supported.clamp(API_VERSION, TARGET_API_VERSION);
+}
+
+#[allow(deprecated)]
+pub fn create_instance(_window: Option<&Window>, _entry: &Entry, _identity: &AppIdentity, _data: &mut AppData) -> Result<Instance> {
+
+    let instance_version = _entry.version()?;
+    let api_version = negotiate_api_version(instance_version);
+    info!("The Vulkan loader supports {}; requesting API version {}.", instance_version, api_version);
+    _data.api_version = Some(api_version);
+
+    let application_info = vk::ApplicationInfo::builder()
+    .application_name(_identity.app_name.as_bytes_with_nul())
+    .application_version(_identity.app_version)
+    .engine_name(_identity.engine_name.as_bytes_with_nul())
+    .engine_version(_identity.engine_version)
+    .api_version(u32::from(api_version));
+
+    let available_extensions: HashSet<vk::ExtensionName>;
+    unsafe {
+        available_extensions = _entry
+        .enumerate_instance_extension_properties(None)?
+        .iter()
+        .map(|e| e.extension_name)
+        .collect::<HashSet<_>>();
+    }
+
+    let available_layers: HashSet<vk::StringArray<256>>;
+    unsafe {
+         available_layers = _entry
+        .enumerate_instance_layer_properties()?
+        .iter()
+        .map(|l| l.layer_name)
+        .collect::<HashSet<_>>();
+    }
+
+    if _data.validation && !available_layers.contains(&VALIDATION_LAYER) {
+        return Err(anyhow!("Validation layer requested but not supported!"))
+    }
+
+    let layers = if _data.validation {
+        vec![VALIDATION_LAYER.as_ptr()]
+    } else {
+        Vec::new()
+    };
+
+    let mut extensions = _window
+    .map(|w| vk_window::get_required_instance_extensions(w))
+    .unwrap_or(&[])
+    .iter()
+    .map(|e| e.as_ptr())
+    .collect::<Vec<_>>();
+
+    if _data.validation {
+        open_validation_log();
+    }
+
+    let debug_extension = if _data.validation {
+        let mut available_extensions = available_extensions.clone();
+        unsafe {
+            let properties = _entry.enumerate_instance_extension_properties(Some(VALIDATION_LAYER.as_cstr().to_bytes_with_nul()))?;
+            available_extensions.extend(properties.iter().map(|e| e.extension_name));
+        }
+        choose_debug_extension(&available_extensions)
+    } else {
+        None
+    };
+
+    match debug_extension {
+        Some(DebugExtension::Utils) => extensions.push(vk::EXT_DEBUG_UTILS_EXTENSION.name.as_ptr()),
+        Some(DebugExtension::Report) => {
+            warn!("VK_EXT_debug_utils is unavailable; falling back to VK_EXT_debug_report.");
+            extensions.push(vk::EXT_DEBUG_REPORT_EXTENSION.name.as_ptr());
+        }
+        None if _data.validation => warn!("No debug messenger extension available; validation output will not be logged."),
+        None => ()
+    }
+
+    _data.portability_mode = PortabilityMode::from_env()?;
+    let enumeration_available = available_extensions.contains(&vk::KHR_PORTABILITY_ENUMERATION_EXTENSION.name);
+    let auto = cfg!(target_os = "macos") && instance_version >= PORTABILITY_MACOS_VERSION;
+    if _data.portability_mode == PortabilityMode::Force && !enumeration_available {
+        warn!("VULCAN_PORTABILITY=force, but the loader doesn't offer VK_KHR_portability_enumeration.");
+    }
+    _data.portability = use_portability(_data.portability_mode, auto, enumeration_available);
+    if _data.portability {
+        info!("Enabling extension for macOS Portability.");
+        extensions.push(vk::KHR_GET_PHYSICAL_DEVICE_PROPERTIES2_EXTENSION.name.as_ptr());
+        extensions.push(vk::KHR_PORTABILITY_ENUMERATION_EXTENSION.name.as_ptr());
+    }
+
+    let severity = debug_severity_from_env()?;
+    let mut debug_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
+        .message_severity(severity)
+        .message_type(
+            vk::DebugUtilsMessageTypeFlagsEXT::GENERAL |
+            vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION |
+            vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+        )
+        .user_callback(Some(debug_callback));
+
+    let mut try_create = |extensions: &[*const c_char], portability: bool| {
+        let flags = if portability {
+            vk::InstanceCreateFlags::ENUMERATE_PORTABILITY_KHR
+        } else {
+            vk::InstanceCreateFlags::empty()
+        };
+
+        let mut info = vk::InstanceCreateInfo::builder()
+        .application_info(&application_info)
+        .enabled_layer_names(&layers)
+        .enabled_extension_names(extensions)
+        .flags(flags);
+
+        if debug_extension == Some(DebugExtension::Utils) {
+            info = info.push_next(&mut debug_info);
+        }
+
+        unsafe {
+            return _entry.create_instance(&info, None);
+        }
+    };
+
+    let mut result = try_create(&extensions, _data.portability);
+
+    // Some loader configurations only expose a portability driver (MoltenVK)
+    // through portability enumeration, which the OS/version check can miss.
+    if matches!(result, Err(vk::ErrorCode::INCOMPATIBLE_DRIVER))
+    && _data.portability_mode == PortabilityMode::Auto
+    && enumeration_available
+    && !_data.portability {
+        warn!("Instance creation failed with ERROR_INCOMPATIBLE_DRIVER; retrying with portability enumeration.");
+        extensions.push(vk::KHR_GET_PHYSICAL_DEVICE_PROPERTIES2_EXTENSION.name.as_ptr());
+        extensions.push(vk::KHR_PORTABILITY_ENUMERATION_EXTENSION.name.as_ptr());
+        _data.portability = true;
+        result = try_create(&extensions, true);
+    }
+
+    let instance = result?;
+    track_created("Instance");
+
+    _data.instance_layers = names_from_ptrs(&layers);
+    _data.instance_extensions = names_from_ptrs(&extensions);
+    debug!("Enabled instance layers: {:?}", _data.instance_layers);
+    debug!("Enabled instance extensions: {:?}", _data.instance_extensions);
+
+    let messenger_result = match debug_extension {
+        Some(DebugExtension::Utils) => unsafe {
+            instance.create_debug_utils_messenger_ext(&debug_info, None).map(|m| {
+                _data.messenger = m;
+                track_created("DebugUtilsMessengerEXT");
+            })
+        }
+        Some(DebugExtension::Report) => {
+            let report_info = vk::DebugReportCallbackCreateInfoEXT::builder()
+            .flags(debug_report_flags(severity))
+            .callback(Some(debug_report_callback));
+
+            unsafe {
+                instance.create_debug_report_callback_ext(&report_info, None).map(|c| {
+                    _data.debug_report_callback = c;
+                    track_created("DebugReportCallbackEXT");
+                })
+            }
+        }
+        None => Ok(())
+    };
+
+    if let Err(error) = messenger_result {
+        unsafe {
+            destroy_instance_objects(&instance, _data);
+        }
+        return Err(error.into());
+    }
+
+    Ok(instance)
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn portability_override_needs_the_extension() {
+        assert_eq!(PortabilityMode::parse("FORCE").unwrap(), PortabilityMode::Force);
+        assert!(PortabilityMode::parse("on").is_err());
+
+        assert!(use_portability(PortabilityMode::Auto, true, true));
+        assert!(!use_portability(PortabilityMode::Auto, false, true));
+        assert!(!use_portability(PortabilityMode::Auto, true, false));
+        assert!(use_portability(PortabilityMode::Force, false, true));
+        assert!(!use_portability(PortabilityMode::Force, false, false));
+        assert!(!use_portability(PortabilityMode::Off, true, true));
+    }
+
+    #[test]
+    fn api_version_is_capped_at_the_target() {
+        assert_eq!(negotiate_api_version(Version::new(1, 0, 0)), Version::new(1, 0, 0));
+        assert_eq!(negotiate_api_version(Version::new(1, 2, 189)), Version::new(1, 2, 0));
+        assert_eq!(negotiate_api_version(Version::new(1, 4, 303)), TARGET_API_VERSION);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn debug_utils_is_preferred_over_debug_report() {
+        let both = HashSet::from([vk::EXT_DEBUG_UTILS_EXTENSION.name, vk::EXT_DEBUG_REPORT_EXTENSION.name]);
+        assert_eq!(choose_debug_extension(&both), Some(DebugExtension::Utils));
+
+        let report = HashSet::from([vk::EXT_DEBUG_REPORT_EXTENSION.name]);
+        assert_eq!(choose_debug_extension(&report), Some(DebugExtension::Report));
+
+        assert_eq!(choose_debug_extension(&HashSet::new()), None);
+    }
+
+    #[test]
+    fn enabled_names_round_trip() {
+        let names = [VALIDATION_LAYER.as_ptr(), vk::KHR_SWAPCHAIN_EXTENSION.name.as_ptr()];
+        assert_eq!(names_from_ptrs(&names), ["VK_LAYER_KHRONOS_validation", "VK_KHR_swapchain"]);
+    }
+
+    #[test]
+    fn debug_severity_includes_higher_levels() {
+        let warning = parse_debug_severity("Warning").unwrap();
+        assert!(warning.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR));
+        assert!(!warning.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO));
+        assert_eq!(parse_debug_severity("verbose").unwrap(), vk::DebugUtilsMessageSeverityFlagsEXT::all());
+        assert!(parse_debug_severity("trace").is_err());
+    }
+
+    #[test]
+    fn validation_log_lines_carry_timestamp_and_severity() {
+        let line = format_validation_line(Duration::from_millis(12_345), "ERROR", "VALIDATION", "bad handle");
+        assert_eq!(line, "[12.345] ERROR (VALIDATION) bad handle");
+    }
+}
@@ -2,358 +2,152 @@
     dead_code,
     unused_variables,
     clippy::too_many_arguments,
-    clippy::unnecessary_wraps
+    clippy::unnecessary_wraps,
+    clippy::needless_return,
+    clippy::single_match,
+    clippy::unnecessary_unwrap
 )]
 
+mod app;
+mod config;
+mod instance;
+mod device;
+mod swapchain;
+mod pipeline;
+mod memory;
+mod commands;
+mod handles;
+mod camera;
+mod descriptors;
+mod model;
+mod texture;
+mod offscreen;
+mod input;
+mod color;
+mod signal;
+
 use anyhow::{anyhow, Result};
-use thiserror::Error;
 
 use log::*;
 
-use vk::QueueFamilyProperties;
-use winit::application::ApplicationHandler;
-use winit::dpi::LogicalSize;
-use winit::event::WindowEvent;
-use winit::keyboard::*;
-use winit::event_loop::{ActiveEventLoop, EventLoop};
-use winit::window::{self, Window};
+use winit::event_loop::EventLoop;
 
 use vulkanalia::loader::{LibloadingLoader, LIBRARY};
 use vulkanalia::prelude::v1_0::*;
-use vulkanalia::Version;
-use vulkanalia::vk::ExtDebugUtilsExtension;
-use vulkanalia::window as vk_window;
-
-use std::collections::HashSet;
-use std::ffi::CStr;
-use std::os::raw::c_void;
-
-const PORTABILITY_MACOS_VERSION: Version = Version::new(1,3,216);
-const VALIDATION_ENABLED: bool = cfg!(debug_assertions);
-const VALIDATION_LAYER: vk::ExtensionName = vk::ExtensionName::from_bytes(b"VK_LAYER_KHRONOS_validation");
-
-#[derive(Default ,Debug)]
-struct App {
-    window: Option<Window>,
-    app: Option<VulkanApp>
-}
-
-#[derive(Clone, Debug)]
-struct VulkanApp {
-    entry: Entry,
-    instance: Instance,
-    data: AppData,
-    device: Device,
-}
-
-impl VulkanApp {
-    fn create(window: &Window) -> Result<Self> {
-
-        let loader: LibloadingLoader;
-        unsafe {
-            loader = LibloadingLoader::new(LIBRARY)?;
-        }
-
-        let entry: Entry;
-        unsafe {
-            entry = Entry::new(loader).map_err(|b| anyhow!("{}", b))?;
-        }
-        let mut data = AppData::default();
-        let instance = create_instance(window, &entry, &mut data)?;
-        let device = create_logical_decice(&entry, &instance, &mut data)?;
-
-        return Ok(Self {entry, instance, data, device})
-    }
-
-    unsafe fn render(&mut self, window: &Window) -> Result<()> {
-        return Ok(());
-    }
 
-    unsafe fn destroy(&mut self) {
-        if VALIDATION_ENABLED {
-            self.instance.destroy_debug_utils_messenger_ext(self.data.messenger, None);
-        }
-
-        self.device.destroy_device(None);
-        self.instance.destroy_instance(None);
-    }
-}
+use std::path::Path;
 
-impl ApplicationHandler for App {
-    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        let window_props = Window::default_attributes()
-        .with_title("Vulkan Testin (Rust)")
-        .with_inner_size(LogicalSize::new(1280,720));
+use crate::app::{App, AppData, FrameLimiter, ShaderWatch, VulkanApp};
+use crate::config::AppConfig;
+use crate::instance::{create_instance, destroy_instance_objects};
+use crate::device::{RequiredFeatures, print_device_report};
+use crate::pipeline::{VERT_SHADER_PATH, FRAG_SHADER_PATH};
+use crate::handles::leaked_handles;
+use crate::signal::install_interrupt_handler;
 
-        self.window = Some(event_loop.create_window(window_props).unwrap());
-        self.app = Some(VulkanApp::create(self.window.as_ref().unwrap()).unwrap());
-    }
-
-    fn window_event(
-            &mut self,
-            event_loop: &ActiveEventLoop,
-            window_id: window::WindowId,
-            event: WindowEvent,
-        ) {
-        match event {
-            WindowEvent::KeyboardInput { device_id, event, is_synthetic } => {
-                match event.logical_key {
-                    Key::Named(NamedKey::Escape) => {
-                        event_loop.exit();
-                    }
-                    _ => ()
-                }
-            }
-
-            WindowEvent::CloseRequested => {
-                event_loop.exit();
-            }
-
-            _ => ()
-        }
-    }
+fn main() -> Result<()> {
+    std::env::set_var("RUST_LOG", "debug");
+    pretty_env_logger::init();
 
-    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
-        // request redraw when other events have passed
-        self.window.as_mut().unwrap().request_redraw();
+    let config = AppConfig::load()?;
+    if config.info {
+        run_info(&config)?;
+        return check_leaks();
     }
-
-    fn exiting(&mut self, event_loop: &ActiveEventLoop) {
-        if self.app.is_some() {
-            unsafe {
-                self.app.as_mut().unwrap().destroy();
-            }
-        }
+    if config.headless.is_some() {
+        run_headless(&config)?;
+        return check_leaks();
     }
 
-}
-
-#[derive(Clone, Debug, Default)]
-struct AppData {
-    messenger: vk::DebugUtilsMessengerEXT,
-    physical_device: vk::PhysicalDevice,
-    graphics_queue: vk::Queue
-}
-
-#[derive(Debug, Error)]
-#[error("Missing {0}.")]
-pub struct SuitabilityError(pub &'static str);
-
-struct QueueFamilyIndices {
-    graphics: u32
-}
-
-impl QueueFamilyIndices {
-    fn get(_instance: &Instance, _data: &AppData, _p_device: vk::PhysicalDevice) -> Result<Self> {
-        let properties: Vec<QueueFamilyProperties>;
-        let graphics: Option<u32>;
-
-        unsafe {
-            properties = _instance.get_physical_device_queue_family_properties(_p_device);
-
-            graphics = properties
-            .iter()
-            .position(|p| p.queue_flags.contains(vk::QueueFlags::GRAPHICS))
-            .map(|i| i as u32);
-
-        }
+    //Window generation
+    let event_loop = EventLoop::new()?;
+    let mut main_app = App {
+        idle: config.idle,
+        reset_window: config.reset_window,
+        frame_limiter: config.max_fps.map(FrameLimiter::new),
+        config,
+        ..Default::default()
+    };
 
-        if let Some(graphics) = graphics {
-            return Ok(Self { graphics});
+    if main_app.config.hot_reload {
+        if cfg!(debug_assertions) {
+            let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+            main_app.shader_watch = Some(ShaderWatch::new(vec![root.join(VERT_SHADER_PATH), root.join(FRAG_SHADER_PATH)]));
         } else {
-            return Err(anyhow!(SuitabilityError("Missing required queue families.")))
+            warn!("--hot-reload is only available in debug builds.");
         }
-
     }
-}
-
-unsafe fn pick_physical_device(_instance: &Instance, _data: &mut AppData) -> Result<()> {
-
-    for device in _instance.enumerate_physical_devices()? {
-        let properties = _instance.get_physical_device_properties(device);
-
-        if let Err(error) = check_physical_device(_instance, _data, device) {
-            warn!("Skipping physical device ('{}'): {}", properties.device_name, error);
-        } else {
-            info!("Selected physical device ('{}').", properties.device_name);
-            _data.physical_device = device;
-            return Ok(());
-        }
+    
+    // without it Ctrl+C kills the process before anything is destroyed
+    if let Err(error) = install_interrupt_handler() {
+        warn!("{}", error);
     }
 
-    return Err(anyhow!("Failed to find suitable physical device."));
-
-}
-
-unsafe fn check_physical_device(_instance: &Instance, _data: &AppData, _p_device: vk::PhysicalDevice) -> Result<()> {
-
-    let properties = _instance.get_physical_device_properties(_p_device);
-    if properties.device_type != vk::PhysicalDeviceType::DISCRETE_GPU 
-    && properties.device_type != vk::PhysicalDeviceType::INTEGRATED_GPU {
-        return Err(anyhow!(SuitabilityError("Only discrete and integrated GPUs are supported.")));
-    }
+    //App
+    event_loop.run_app(&mut main_app)?;
 
-    let features = _instance.get_physical_device_features(_p_device);
-    if features.geometry_shader != vk::TRUE {
-        return Err(anyhow!(SuitabilityError("Missing geometry shader support.")));
+    check_leaks()?;
+    if main_app.init_error.is_some() {
+        // already logged when it happened
+        std::process::exit(1);
     }
 
-    QueueFamilyIndices::get(_instance,_data,_p_device)?;
-
     return Ok(());
 
 }
 
-fn create_logical_decice(_entry: &Entry, _instance: &Instance, _data: &mut AppData) -> Result<Device> {
-
-    let indices = QueueFamilyIndices::get(_instance, _data, _data.physical_device)?;
-
-    let queue_priorities = &[1.0];
-    let queue_info = vk::DeviceQueueCreateInfo::builder()
-    .queue_family_index(indices.graphics)
-    .queue_priorities(queue_priorities);
-
-    let layers = if VALIDATION_ENABLED {
-        vec![VALIDATION_LAYER.as_ptr()]
-    } else {
-        Vec::new()
-    };
-
-    let mut extensions = Vec::new();
-
-    if cfg!(target_os = "macos") && _entry.version()? >= PORTABILITY_MACOS_VERSION {
-        extensions.push(vk::KHR_PORTABILITY_SUBSET_EXTENSION.name.as_ptr());
-    }
-
-    let features = vk::PhysicalDeviceFeatures::builder();
+/// Renders a single frame offscreen and saves it to `headless.png`.
+fn run_headless(_config: &AppConfig) -> Result<()> {
 
-    let queue_infos = &[queue_info];
-    let info = vk::DeviceCreateInfo::builder()
-    .queue_create_infos(queue_infos)
-    .enabled_layer_names(&layers)
-    .enabled_extension_names(&extensions)
-    .enabled_features(&features);
-
-    let device: Device;
+    let mut app = VulkanApp::create(None, _config, RequiredFeatures::default())?;
+    let path = Path::new("headless.png");
+    let result = unsafe { app.render_headless().and_then(|()| app.save_screenshot(path)) };
     unsafe {
-        device = _instance.create_device(_data.physical_device, &info, None)?;
+        app.destroy();
     }
+    result?;
+    info!("Saved headless frame to {}.", path.display());
 
-    unsafe {
-        _data.graphics_queue = device.get_device_queue(indices.graphics, 0);
-    }
-
-    return Ok(device);
-
-}
-
-extern "system" fn debug_callback(severity: vk::DebugUtilsMessageSeverityFlagsEXT,
-    type_: vk::DebugUtilsMessageTypeFlagsEXT,
-    data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _: *mut c_void,
-) -> vk::Bool32 {
-    let data = unsafe { *data };
-    let message = unsafe { CStr::from_ptr(data.message) }.to_string_lossy();
-    
-    if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::ERROR {
-        error!("({:?}) {}", type_, message);
-    } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING {
-        warn!("({:?}) {}", type_, message);
-    } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::INFO {
-        info!("({:?}) {}", type_, message);
-    } else {
-        trace!("({:?}) {}", type_, message);
-    }
+    return Ok(());
 
-    return vk::FALSE;
 }
 
+/// Prints the loader version, instance extensions and every physical device
+/// for bug reports (--info).
+fn run_info(_config: &AppConfig) -> Result<()> {
 
-fn create_instance(_window: &Window, _entry: &Entry, _data: &mut AppData) -> Result<Instance> {
-
-    let application_info = vk::ApplicationInfo::builder()
-    .application_name(b"Vulcan Tutorial\0")
-    .application_version(vk::make_version(1, 0, 0))
-    .engine_name(b"No Engine\0")
-    .engine_version(vk::make_version(1, 0, 0))
-    .api_version(vk::make_version(1, 0, 0));
-
-    let available_layers: HashSet<vk::StringArray<256>>;
-    unsafe {
-         available_layers = _entry
-        .enumerate_instance_layer_properties()?
-        .iter()
-        .map(|l| l.layer_name)
-        .collect::<HashSet<_>>();
-    }
-
-    if VALIDATION_ENABLED && !available_layers.contains(&VALIDATION_LAYER) {
-        return Err(anyhow!("Validation layer requested but not supported!"))
-    }
+    let entry = unsafe { Entry::new(LibloadingLoader::new(LIBRARY)?).map_err(|b| anyhow!("{}", b))? };
+    // the report is for users' machines, which often lack the validation layers
+    let mut data = AppData { validation: false, ..Default::default() };
+    let instance = create_instance(None, &entry, &_config.identity, &mut data)?;
 
-    let layers = if VALIDATION_ENABLED {
-        vec![VALIDATION_LAYER.as_ptr()]
-    } else {
-        Vec::new()
-    };
-
-    let mut extensions = vk_window::get_required_instance_extensions(_window)
+    println!("Vulkan loader version: {}", entry.version()?);
+    let mut extensions = unsafe { entry.enumerate_instance_extension_properties(None)? }
     .iter()
-    .map(|e| e.as_ptr())
+    .map(|e| e.extension_name.to_string())
     .collect::<Vec<_>>();
-
-    if VALIDATION_ENABLED {
-        extensions.push(vk::EXT_DEBUG_UTILS_EXTENSION.name.as_ptr());
+    extensions.sort();
+    println!("Instance extensions ({}):", extensions.len());
+    for name in &extensions {
+        println!("  {}", name);
     }
 
-    let flags = if 
-    cfg!(target_os = "macos") && _entry.version()? >= PORTABILITY_MACOS_VERSION {
-        info!("Enabling extension for macOS Portability.");
-        extensions.push(vk::KHR_GET_PHYSICAL_DEVICE_PROPERTIES2_EXTENSION.name.as_ptr());
-        extensions.push(vk::KHR_PORTABILITY_ENUMERATION_EXTENSION.name.as_ptr());
-        vk::InstanceCreateFlags::ENUMERATE_PORTABILITY_KHR
-    } else {
-        vk::InstanceCreateFlags::empty()
-    };
-
-    let mut info = vk::InstanceCreateInfo::builder()
-    .application_info(&application_info)
-    .enabled_layer_names(&layers)
-    .enabled_extension_names(&extensions)
-    .flags(flags);
-
-    let mut debug_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
-        .message_severity(vk::DebugUtilsMessageSeverityFlagsEXT::all())
-        .message_type(
-            vk::DebugUtilsMessageTypeFlagsEXT::GENERAL |
-            vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION |
-            vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
-        )
-        .user_callback(Some(debug_callback));
-
-    if VALIDATION_ENABLED {
-        info = info.push_next(&mut debug_info);
-    }
-
-    let instance: Instance;
+    let result = unsafe { print_device_report(&instance) };
     unsafe {
-        instance = _entry.create_instance(&info, None)?;
+        destroy_instance_objects(&instance, &mut data);
     }
 
-    Ok(instance)
+    return result;
 
 }
 
-fn main() -> Result<()> {
-    std::env::set_var("RUST_LOG", "debug");
-    pretty_env_logger::init();
+fn check_leaks() -> Result<()> {
 
-    //Window generation
-    let event_loop = EventLoop::new()?;
-    let mut main_app = App::default();
-    
-    //App
-    event_loop.run_app(&mut main_app)?;
+    let leaks = leaked_handles();
+    for (kind, count) in &leaks {
+        error!("Leaked {} {} handle(s).", count, kind);
+    }
+    debug_assert!(leaks.is_empty(), "Vulkan handles leaked at shutdown.");
 
     return Ok(());
 
@@ -0,0 +1,647 @@
+//! Device memory sub-allocation and buffer helpers.
+
+use anyhow::{anyhow, Result};
+
+use log::*;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::app::AppData;
+use crate::commands::{CommandRecorder, RecorderHandle};
+use crate::handles::{track_created, track_destroyed};
+
+/// Creates a buffer of `_size` bytes with memory from `_location` bound to it.
+pub unsafe fn create_buffer(
+    _device: &Device,
+    _data: &mut AppData,
+    _resource: &str,
+    _size: u64,
+    _usage: vk::BufferUsageFlags,
+    _location: MemoryLocation,
+) -> Result<(vk::Buffer, Allocation)> {
+
+    let sharing_mode = match _data.buffer_queue_families.len() > 1 {
+        true => vk::SharingMode::CONCURRENT,
+        false => vk::SharingMode::EXCLUSIVE,
+    };
+    let info = vk::BufferCreateInfo::builder()
+    .size(_size)
+    .usage(_usage)
+    .sharing_mode(sharing_mode)
+    .queue_family_indices(&_data.buffer_queue_families);
+
+    let buffer = _device.create_buffer(&info, None)?;
+    track_created("Buffer");
+
+    let requirements = _device.get_buffer_memory_requirements(buffer);
+    let allocation = match _data.allocator.allocate(_device, _resource, requirements, _location) {
+        Ok(allocation) => allocation,
+        Err(error) => {
+            _device.destroy_buffer(buffer, None);
+            track_destroyed("Buffer");
+            return Err(error);
+        }
+    };
+
+    if let Err(error) = _device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) {
+        destroy_buffer(_device, _data, buffer, allocation);
+        return Err(anyhow!(error));
+    }
+
+    return Ok((buffer, allocation));
+
+}
+
+pub unsafe fn destroy_buffer(_device: &Device, _data: &mut AppData, _buffer: vk::Buffer, _allocation: Allocation) {
+    _device.destroy_buffer(_buffer, None);
+    track_destroyed("Buffer");
+    _data.allocator.free(_allocation);
+}
+
+/// Creates a 2D image with a single layer and binds memory from `_location`
+/// to it.
+pub unsafe fn create_image(
+    _device: &Device,
+    _data: &mut AppData,
+    _resource: &str,
+    _extent: vk::Extent2D,
+    _mip_levels: u32,
+    _format: vk::Format,
+    _samples: vk::SampleCountFlags,
+    _usage: vk::ImageUsageFlags,
+    _location: MemoryLocation,
+) -> Result<(vk::Image, Allocation)> {
+
+    let info = vk::ImageCreateInfo::builder()
+    .image_type(vk::ImageType::_2D)
+    .format(_format)
+    .extent(vk::Extent3D { width: _extent.width, height: _extent.height, depth: 1 })
+    .mip_levels(_mip_levels)
+    .array_layers(1)
+    .samples(_samples)
+    .tiling(vk::ImageTiling::OPTIMAL)
+    .usage(_usage)
+    .sharing_mode(vk::SharingMode::EXCLUSIVE)
+    .initial_layout(vk::ImageLayout::UNDEFINED);
+
+    let image = _device.create_image(&info, None)?;
+    track_created("Image");
+
+    let requirements = _device.get_image_memory_requirements(image);
+    let allocation = _data.allocator.allocate(_device, _resource, requirements, _location)
+    .and_then(|allocation| match _device.bind_image_memory(image, allocation.memory, allocation.offset) {
+        Ok(()) => Ok(allocation),
+        Err(error) => {
+            _data.allocator.free(allocation);
+            Err(anyhow!(error))
+        }
+    });
+
+    return match allocation {
+        Ok(allocation) => Ok((image, allocation)),
+        Err(error) => {
+            _device.destroy_image(image, None);
+            track_destroyed("Image");
+            Err(error)
+        }
+    };
+
+}
+
+/// Copies `_size` bytes between buffers with a one-shot command buffer on the
+/// transfer queue, waiting for the copy to finish.
+pub unsafe fn copy_buffer(_device: &Device, _data: &AppData, _source: vk::Buffer, _destination: vk::Buffer, _size: u64) -> Result<()> {
+
+    let mut recorder = CommandRecorder::transfer(_data).begin(_device)?;
+    recorder.copy_buffer(_source, _destination, _size);
+
+    return recorder.flush();
+
+}
+
+/// Uploads `_contents` into a new device-local buffer through a temporary
+/// host-visible staging buffer. `TRANSFER_DST` is added to `_usage`.
+pub unsafe fn upload_to_device_local<T: Copy>(
+    _device: &Device,
+    _data: &mut AppData,
+    _resource: &str,
+    _contents: &[T],
+    _usage: vk::BufferUsageFlags,
+) -> Result<(vk::Buffer, Allocation)> {
+
+    let mut recorder = CommandRecorder::transfer(_data).begin(_device)?;
+    let upload = stage_upload(_device, _data, &mut recorder, _resource, _contents, _usage)?;
+    if let Err(error) = recorder.flush() {
+        discard_upload(_device, _data, upload);
+        return Err(error);
+    }
+
+    return Ok(finish_upload(_device, _data, upload));
+
+}
+
+/// A device-local buffer whose contents wait in a staging buffer for the
+/// copy recorded by `stage_upload` to run.
+#[derive(Clone, Copy, Debug)]
+pub struct StagedUpload {
+    pub buffer: vk::Buffer,
+    pub allocation: Allocation,
+    pub staging: vk::Buffer,
+    pub staging_allocation: Allocation
+}
+
+/// Creates a device-local buffer for `_contents` and records the copy from a
+/// staging buffer into `_recorder`, so several uploads can share one
+/// submission. Pass the result to `finish_upload` once the batch is flushed,
+/// or to `discard_upload` if it isn't.
+pub unsafe fn stage_upload<T: Copy>(
+    _device: &Device,
+    _data: &mut AppData,
+    _recorder: &mut RecorderHandle,
+    _resource: &str,
+    _contents: &[T],
+    _usage: vk::BufferUsageFlags,
+) -> Result<StagedUpload> {
+
+    let size = std::mem::size_of_val(_contents) as u64;
+    if size == 0 {
+        return Err(anyhow!("Nothing to upload for the {}.", _resource));
+    }
+
+    let (staging, staging_allocation) = create_buffer(
+        _device,
+        _data,
+        _resource,
+        size,
+        vk::BufferUsageFlags::TRANSFER_SRC,
+        MemoryLocation::CpuToGpu,
+    )?;
+    std::ptr::copy_nonoverlapping(_contents.as_ptr() as *const u8, staging_allocation.mapped, size as usize);
+
+    let (buffer, allocation) = match create_buffer(
+        _device,
+        _data,
+        _resource,
+        size,
+        _usage | vk::BufferUsageFlags::TRANSFER_DST,
+        MemoryLocation::GpuOnly,
+    ) {
+        Ok(created) => created,
+        Err(error) => {
+            destroy_buffer(_device, _data, staging, staging_allocation);
+            return Err(error);
+        }
+    };
+    _recorder.copy_buffer(staging, buffer, size);
+
+    return Ok(StagedUpload { buffer, allocation, staging, staging_allocation });
+
+}
+
+/// Frees the staging buffer of a flushed upload and hands over the filled
+/// device-local buffer.
+pub unsafe fn finish_upload(_device: &Device, _data: &mut AppData, _upload: StagedUpload) -> (vk::Buffer, Allocation) {
+
+    destroy_buffer(_device, _data, _upload.staging, _upload.staging_allocation);
+
+    return (_upload.buffer, _upload.allocation);
+
+}
+
+/// Destroys both buffers of an upload that was never flushed or whose batch
+/// failed.
+pub unsafe fn discard_upload(_device: &Device, _data: &mut AppData, _upload: StagedUpload) {
+
+    destroy_buffer(_device, _data, _upload.staging, _upload.staging_allocation);
+    destroy_buffer(_device, _data, _upload.buffer, _upload.allocation);
+
+}
+
+/// Where an allocation should live. Each location has the memory properties
+/// it can't do without and a superset it would rather have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryLocation {
+    // only the GPU touches it: render targets, static vertex data
+    GpuOnly,
+    // written by the CPU, read by the GPU: staging and uniform buffers
+    CpuToGpu,
+    // written by the GPU, read back by the CPU: screenshots, query readback
+    GpuToCpu
+}
+
+impl MemoryLocation {
+    pub fn required(self) -> vk::MemoryPropertyFlags {
+        return match self {
+            MemoryLocation::GpuOnly => vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            MemoryLocation::CpuToGpu | MemoryLocation::GpuToCpu => {
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT
+            }
+        };
+    }
+
+    pub fn preferred(self) -> vk::MemoryPropertyFlags {
+        return match self {
+            MemoryLocation::GpuOnly => self.required(),
+            MemoryLocation::CpuToGpu => self.required() | vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            MemoryLocation::GpuToCpu => self.required() | vk::MemoryPropertyFlags::HOST_CACHED,
+        };
+    }
+}
+
+/// Size of the `vk::DeviceMemory` blocks the allocator carves resources out
+/// of. Requests bigger than this get a block of their own.
+pub const MEMORY_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Free ranges of one memory block as sorted `(offset, size)` pairs, so the
+/// sub-allocation bookkeeping can be tested without a device.
+#[derive(Clone, Debug, Default)]
+pub struct BlockRanges {
+    pub free: Vec<(u64, u64)>
+}
+
+impl BlockRanges {
+    pub fn new(size: u64) -> Self {
+        return Self { free: vec![(0, size)] };
+    }
+
+    /// First-fit: returns the offset of `size` bytes aligned to `alignment`,
+    /// or `None` if no free range is large enough.
+    pub fn allocate(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        let alignment = alignment.max(1);
+        let (index, offset) = self.free.iter().enumerate().find_map(|(i, &(start, len))| {
+            let offset = start.div_ceil(alignment) * alignment;
+            (offset + size <= start + len).then_some((i, offset))
+        })?;
+
+        let (start, len) = self.free.remove(index);
+        let end = start + len;
+        if offset + size < end {
+            self.free.insert(index, (offset + size, end - offset - size));
+        }
+        if offset > start {
+            self.free.insert(index, (start, offset - start));
+        }
+
+        return Some(offset);
+    }
+
+    /// Returns a range to the free list, merging it with its neighbours.
+    pub fn free(&mut self, offset: u64, size: u64) {
+        let index = self.free.partition_point(|&(start, _)| start < offset);
+        self.free.insert(index, (offset, size));
+
+        if index + 1 < self.free.len() && offset + size == self.free[index + 1].0 {
+            self.free[index].1 += self.free.remove(index + 1).1;
+        }
+        if index > 0 && self.free[index - 1].0 + self.free[index - 1].1 == offset {
+            self.free[index - 1].1 += self.free.remove(index).1;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryBlock {
+    pub memory: vk::DeviceMemory,
+    pub memory_type: u32,
+    // persistently mapped for host-visible types, null otherwise
+    pub mapped: *mut u8,
+    pub ranges: BlockRanges
+}
+
+/// A sub-range of one of the allocator's blocks. Bind resources with
+/// `memory` and `offset`; `mapped` points at `offset` for host-visible
+/// locations and is null otherwise.
+#[derive(Clone, Copy, Debug)]
+pub struct Allocation {
+    pub memory: vk::DeviceMemory,
+    pub offset: u64,
+    pub size: u64,
+    pub mapped: *mut u8,
+    pub block: usize
+}
+
+/// Sub-allocates buffers and images out of a few large `vk::DeviceMemory`
+/// blocks instead of one allocation per resource, which keeps us far below
+/// `maxMemoryAllocationCount`. Blocks stay around until `destroy`.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryAllocator {
+    pub memory_properties: vk::PhysicalDeviceMemoryProperties,
+    // linear and optimal resources may end up next to each other
+    pub granularity: u64,
+    pub blocks: Vec<MemoryBlock>
+}
+
+impl MemoryAllocator {
+    pub unsafe fn new(instance: &Instance, physical_device: vk::PhysicalDevice) -> Self {
+        let limits = instance.get_physical_device_properties(physical_device).limits;
+        return Self {
+            memory_properties: instance.get_physical_device_memory_properties(physical_device),
+            granularity: limits.buffer_image_granularity,
+            blocks: Vec::new(),
+        };
+    }
+
+    pub fn find_memory_type(&self, memory_type_bits: u32, properties: vk::MemoryPropertyFlags) -> Option<u32> {
+        return (0..self.memory_properties.memory_type_count)
+        .find(|i| {
+            let suitable = (memory_type_bits & (1 << i)) != 0;
+            let memory_type = self.memory_properties.memory_types[*i as usize];
+            suitable && memory_type.property_flags.contains(properties)
+        });
+    }
+
+    /// A host-visible memory type to use for `memory_type_bits` when
+    /// `failed_type` (device-local) is out of memory, preferring one on
+    /// another heap; on the same heap it would likely be full as well.
+    pub fn host_visible_fallback(&self, memory_type_bits: u32, failed_type: u32) -> Option<u32> {
+        let types = &self.memory_properties.memory_types;
+        let failed_heap = types[failed_type as usize].heap_index;
+        let candidates = (0..self.memory_properties.memory_type_count)
+        .filter(|&i| i != failed_type && (memory_type_bits & (1 << i)) != 0)
+        .filter(|&i| types[i as usize].property_flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE))
+        .collect::<Vec<_>>();
+
+        return candidates
+        .iter()
+        .find(|&&i| types[i as usize].heap_index != failed_heap)
+        .or(candidates.first())
+        .copied();
+    }
+
+    /// Finds room for `requirements` in an existing block of a suitable
+    /// memory type, allocating a new block if none has space. GPU-only
+    /// resources fall back to host-visible memory when device-local memory
+    /// runs out, which is slower for the GPU but better than failing.
+    pub unsafe fn allocate(
+        &mut self,
+        device: &Device,
+        resource: &str,
+        requirements: vk::MemoryRequirements,
+        location: MemoryLocation,
+    ) -> Result<Allocation> {
+
+        let memory_type = self.find_memory_type(requirements.memory_type_bits, location.preferred())
+        .or_else(|| self.find_memory_type(requirements.memory_type_bits, location.required()))
+        .ok_or_else(|| anyhow!("Failed to find suitable memory type for the {} ({:?}).", resource, location))?;
+
+        return match self.allocate_in_type(device, resource, requirements, memory_type) {
+            Err(error) if location == MemoryLocation::GpuOnly && is_out_of_memory_error(&error) => {
+                let Some(fallback) = self.host_visible_fallback(requirements.memory_type_bits, memory_type) else {
+                    return Err(error);
+                };
+                warn!("{:#}; falling back to host-visible memory type {}.", error, fallback);
+                self.allocate_in_type(device, resource, requirements, fallback)
+            }
+            result => result,
+        };
+    }
+
+    pub unsafe fn allocate_in_type(
+        &mut self,
+        device: &Device,
+        resource: &str,
+        requirements: vk::MemoryRequirements,
+        memory_type: u32,
+    ) -> Result<Allocation> {
+
+        let alignment = requirements.alignment.max(self.granularity);
+
+        for (index, block) in self.blocks.iter_mut().enumerate() {
+            if block.memory_type != memory_type {
+                continue;
+            }
+            if let Some(offset) = block.ranges.allocate(requirements.size, alignment) {
+                return Ok(block_allocation(block, index, offset, requirements.size));
+            }
+        }
+
+        let properties = self.memory_properties.memory_types[memory_type as usize].property_flags;
+        let block_size = requirements.size.max(MEMORY_BLOCK_SIZE);
+        // a full block may not fit when memory is tight, the resource alone might
+        let memory = allocate_memory(device, resource, block_size, memory_type, properties)
+        .map(|memory| (memory, block_size))
+        .or_else(|error| match block_size > requirements.size {
+            true => allocate_memory(device, resource, requirements.size, memory_type, properties)
+            .map(|memory| (memory, requirements.size)),
+            false => Err(error),
+        });
+        let (memory, block_size) = memory?;
+
+        let mapped = match properties.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            true => match device.map_memory(memory, 0, vk::WHOLE_SIZE as u64, vk::MemoryMapFlags::empty()) {
+                Ok(pointer) => pointer as *mut u8,
+                Err(error) => {
+                    device.free_memory(memory, None);
+                    track_destroyed("DeviceMemory");
+                    return Err(anyhow!(error));
+                }
+            },
+            false => std::ptr::null_mut(),
+        };
+
+        let mut block = MemoryBlock { memory, memory_type, mapped, ranges: BlockRanges::new(block_size) };
+        let offset = block.ranges.allocate(requirements.size, alignment)
+        .ok_or_else(|| anyhow!("Fresh memory block too small for the {}.", resource))?;
+        let allocation = block_allocation(&block, self.blocks.len(), offset, requirements.size);
+        self.blocks.push(block);
+
+        return Ok(allocation);
+    }
+
+    pub fn free(&mut self, allocation: Allocation) {
+        self.blocks[allocation.block].ranges.free(allocation.offset, allocation.size);
+    }
+
+    /// Frees every block. Must run before the device is destroyed.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.blocks
+        .drain(..)
+        .for_each(|block| {
+            if !block.mapped.is_null() {
+                device.unmap_memory(block.memory);
+            }
+            device.free_memory(block.memory, None);
+            track_destroyed("DeviceMemory");
+        });
+    }
+}
+
+pub fn block_allocation(_block: &MemoryBlock, _index: usize, _offset: u64, _size: u64) -> Allocation {
+    let mapped = match _block.mapped.is_null() {
+        true => std::ptr::null_mut(),
+        false => unsafe { _block.mapped.add(_offset as usize) },
+    };
+    return Allocation { memory: _block.memory, offset: _offset, size: _size, mapped, block: _index };
+}
+
+/// Allocates a memory block, turning out-of-memory results into an error
+/// that says what was being allocated and how much.
+pub unsafe fn allocate_memory(
+    _device: &Device,
+    _resource: &str,
+    _size: u64,
+    _memory_type: u32,
+    _properties: vk::MemoryPropertyFlags,
+) -> Result<vk::DeviceMemory> {
+
+    let info = vk::MemoryAllocateInfo::builder()
+    .allocation_size(_size)
+    .memory_type_index(_memory_type);
+
+    // the error code stays attached for `is_out_of_memory_error`
+    let memory = match _device.allocate_memory(&info, None) {
+        Ok(memory) => memory,
+        Err(error @ (vk::ErrorCode::OUT_OF_DEVICE_MEMORY | vk::ErrorCode::OUT_OF_HOST_MEMORY)) => {
+            return Err(anyhow!(error).context(format!(
+                "Out of memory allocating {} bytes ({:?}) for the {} ({}). Try a smaller window or lower resolution assets.",
+                _size, _properties, _resource, error
+            )));
+        }
+        Err(error) => return Err(anyhow!(error)),
+    };
+    track_created("DeviceMemory");
+
+    return Ok(memory);
+
+}
+
+pub fn is_out_of_memory_error(_error: &anyhow::Error) -> bool {
+    return matches!(
+        _error.downcast_ref::<vk::ErrorCode>().copied(),
+        Some(vk::ErrorCode::OUT_OF_DEVICE_MEMORY | vk::ErrorCode::OUT_OF_HOST_MEMORY)
+    );
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::instance::API_VERSION;
+    use vulkanalia::loader::{LibloadingLoader, LIBRARY};
+
+    #[test]
+    fn block_ranges_align_and_merge() {
+        let mut ranges = BlockRanges::new(1024);
+        assert_eq!(ranges.allocate(100, 1), Some(0));
+        assert_eq!(ranges.allocate(100, 256), Some(256));
+        assert_eq!(ranges.free, vec![(100, 156), (356, 668)]);
+        assert_eq!(ranges.allocate(1000, 1), None);
+
+        ranges.free(256, 100);
+        ranges.free(0, 100);
+        assert_eq!(ranges.free, vec![(0, 1024)]);
+    }
+
+    #[test]
+    fn device_local_falls_back_to_host_visible_memory() {
+        let mut allocator = MemoryAllocator::default();
+        let properties = &mut allocator.memory_properties;
+        properties.memory_type_count = 3;
+        properties.memory_types[0] = vk::MemoryType { property_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL, heap_index: 0 };
+        properties.memory_types[1] = vk::MemoryType {
+            property_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::HOST_VISIBLE,
+            heap_index: 0,
+        };
+        properties.memory_types[2] = vk::MemoryType { property_flags: vk::MemoryPropertyFlags::HOST_VISIBLE, heap_index: 1 };
+
+        // another heap wins over the full one, which is still better than nothing
+        assert_eq!(allocator.host_visible_fallback(0b111, 0), Some(2));
+        assert_eq!(allocator.host_visible_fallback(0b011, 0), Some(1));
+        assert_eq!(allocator.host_visible_fallback(0b001, 0), None);
+
+        let out_of_memory = anyhow!(vk::ErrorCode::OUT_OF_DEVICE_MEMORY).context("Out of memory for the test buffer");
+        assert!(is_out_of_memory_error(&out_of_memory));
+        assert!(!is_out_of_memory_error(&anyhow!(vk::ErrorCode::DEVICE_LOST)));
+    }
+
+    /// Creates a bare instance (no layers or extensions), or `None` when there
+    /// is no Vulkan loader or driver to test against.
+    pub(crate) unsafe fn test_instance() -> Option<Instance> {
+        let loader = LibloadingLoader::new(LIBRARY).ok()?;
+        let entry = Entry::new(loader).ok()?;
+
+        let application_info = vk::ApplicationInfo::builder()
+        .api_version(API_VERSION.into());
+        let info = vk::InstanceCreateInfo::builder()
+        .application_info(&application_info);
+        return entry.create_instance(&info, None).ok();
+    }
+
+    /// Creates a device on the first GPU with a graphics queue, or `None` when
+    /// there is no Vulkan loader or device to test against.
+    pub(crate) unsafe fn test_device() -> Option<(Instance, Device, AppData)> {
+        let instance = test_instance()?;
+
+        let found = instance.enumerate_physical_devices().unwrap_or_default()
+        .into_iter()
+        .find_map(|p| {
+            instance.get_physical_device_queue_family_properties(p)
+            .iter()
+            .position(|f| f.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+            .map(|family| (p, family as u32))
+        });
+        let Some((physical_device, family)) = found else {
+            instance.destroy_instance(None);
+            return None;
+        };
+
+        let priorities = &[1.0];
+        let queue_infos = &[vk::DeviceQueueCreateInfo::builder()
+        .queue_family_index(family)
+        .queue_priorities(priorities)];
+        let info = vk::DeviceCreateInfo::builder()
+        .queue_create_infos(queue_infos);
+        let device = instance.create_device(physical_device, &info, None).unwrap();
+
+        let pool_info = vk::CommandPoolCreateInfo::builder()
+        .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+        .queue_family_index(family);
+        let data = AppData {
+            physical_device,
+            transfer_queue: device.get_device_queue(family, 0),
+            transfer_command_pool: device.create_command_pool(&pool_info, None).unwrap(),
+            buffer_queue_families: vec![family],
+            allocator: MemoryAllocator::new(&instance, physical_device),
+            ..Default::default()
+        };
+
+        return Some((instance, device, data));
+    }
+
+    #[test]
+    fn device_local_upload_round_trips() {
+        let Some((instance, device, mut data)) = (unsafe { test_device() }) else {
+            eprintln!("No Vulkan device available, skipping.");
+            return;
+        };
+
+        unsafe {
+            let contents: Vec<u8> = (0..=255).collect();
+            let (buffer, allocation) = upload_to_device_local(
+                &device,
+                &mut data,
+                "test buffer",
+                &contents,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+            ).unwrap();
+            let (readback, readback_allocation) = create_buffer(
+                &device,
+                &mut data,
+                "readback buffer",
+                contents.len() as u64,
+                vk::BufferUsageFlags::TRANSFER_DST,
+                MemoryLocation::GpuToCpu,
+            ).unwrap();
+
+            copy_buffer(&device, &data, buffer, readback, contents.len() as u64).unwrap();
+            let read = std::slice::from_raw_parts(readback_allocation.mapped, contents.len());
+            assert_eq!(read, &contents[..]);
+
+            destroy_buffer(&device, &mut data, readback, readback_allocation);
+            destroy_buffer(&device, &mut data, buffer, allocation);
+            device.destroy_command_pool(data.transfer_command_pool, None);
+            data.allocator.destroy(&device);
+            device.destroy_device(None);
+            instance.destroy_instance(None);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Vertex format, OBJ loading and the vertex and index buffers.
+
+use anyhow::{Context, Result};
+
+use cgmath::{Vector2, Vector3};
+
+use log::*;
+
+use vulkanalia::prelude::v1_0::*;
+
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::path::Path;
+
+use crate::app::AppData;
+use crate::commands::CommandRecorder;
+use crate::memory::{stage_upload, finish_upload, discard_upload, destroy_buffer};
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Vertex {
+    pub pos: Vector3<f32>,
+    pub color: Vector3<f32>,
+    pub tex_coord: Vector2<f32>
+}
+
+impl Vertex {
+    pub const fn new(pos: Vector3<f32>, color: Vector3<f32>, tex_coord: Vector2<f32>) -> Self {
+        return Self { pos, color, tex_coord };
+    }
+
+    pub fn binding_description() -> vk::VertexInputBindingDescription {
+        return vk::VertexInputBindingDescription::builder()
+        .binding(0)
+        .stride(size_of::<Vertex>() as u32)
+        .input_rate(vk::VertexInputRate::VERTEX)
+        .build();
+    }
+
+    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 3] {
+        let pos = vk::VertexInputAttributeDescription::builder()
+        .binding(0)
+        .location(0)
+        .format(vk::Format::R32G32B32_SFLOAT)
+        .offset(0)
+        .build();
+
+        let color = vk::VertexInputAttributeDescription::builder()
+        .binding(0)
+        .location(1)
+        .format(vk::Format::R32G32B32_SFLOAT)
+        .offset(size_of::<Vector3<f32>>() as u32)
+        .build();
+
+        let tex_coord = vk::VertexInputAttributeDescription::builder()
+        .binding(0)
+        .location(2)
+        .format(vk::Format::R32G32_SFLOAT)
+        .offset((size_of::<Vector3<f32>>() + size_of::<Vector3<f32>>()) as u32)
+        .build();
+
+        return [pos, color, tex_coord];
+    }
+
+    /// The vertex as bits, so identical vertices can be found with a hash map
+    /// (f32 is neither `Eq` nor `Hash`).
+    pub fn key(&self) -> [u32; 8] {
+        return [
+            self.pos.x, self.pos.y, self.pos.z,
+            self.color.x, self.color.y, self.color.z,
+            self.tex_coord.x, self.tex_coord.y,
+        ].map(f32::to_bits);
+    }
+}
+
+// drawn when no model is given (--model); world space, y up, counter-clockwise
+// seen from the default camera
+pub const TRIANGLE_VERTICES: [Vertex; 3] = [
+    Vertex::new(Vector3::new(0.0, 0.5, 0.0), Vector3::new(1.0, 0.0, 0.0), Vector2::new(0.5, 0.0)),
+    Vertex::new(Vector3::new(-0.5, -0.5, 0.0), Vector3::new(0.0, 0.0, 1.0), Vector2::new(0.0, 1.0)),
+    Vertex::new(Vector3::new(0.5, -0.5, 0.0), Vector3::new(0.0, 1.0, 0.0), Vector2::new(1.0, 1.0)),
+];
+pub const TRIANGLE_INDICES: [u32; 3] = [0, 1, 2];
+
+// color of vertices in files without vertex colors
+pub const DEFAULT_VERTEX_COLOR: Vector3<f32> = Vector3::new(1.0, 1.0, 1.0);
+
+/// Loads the meshes of an OBJ file as one deduplicated vertex and index list.
+/// Materials are ignored.
+pub fn load_model(_path: &Path) -> Result<(Vec<Vertex>, Vec<u32>)> {
+
+    let (models, _) = tobj::load_obj(_path, &tobj::GPU_LOAD_OPTIONS)?;
+    let (vertices, indices) = build_model(&models);
+    info!("Loaded {} ({} vertices, {} triangles).", _path.display(), vertices.len(), indices.len() / 3);
+
+    return Ok((vertices, indices));
+
+}
+
+/// Merges the meshes of `_models` into one vertex list with identical vertices
+/// shared. Missing texture coordinates default to (0, 0) and missing colors to
+/// white; normals aren't part of `Vertex`, so files without them are fine.
+pub fn build_model(_models: &[tobj::Model]) -> (Vec<Vertex>, Vec<u32>) {
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    let mut unique = HashMap::new();
+
+    for mesh in _models.iter().map(|m| &m.mesh) {
+        for &index in &mesh.indices {
+            let i = index as usize;
+            let pos = Vector3::new(mesh.positions[3 * i], mesh.positions[3 * i + 1], mesh.positions[3 * i + 2]);
+            let color = match mesh.vertex_color.get(3 * i..3 * i + 3) {
+                Some(c) => Vector3::new(c[0], c[1], c[2]),
+                None => DEFAULT_VERTEX_COLOR,
+            };
+            // OBJ puts v = 0 at the bottom of the image, Vulkan at the top
+            let tex_coord = match mesh.texcoords.get(2 * i..2 * i + 2) {
+                Some(t) => Vector2::new(t[0], 1.0 - t[1]),
+                None => Vector2::new(0.0, 0.0),
+            };
+
+            let vertex = Vertex::new(pos, color, tex_coord);
+            let index = *unique.entry(vertex.key()).or_insert_with(|| {
+                vertices.push(vertex);
+                return vertices.len() as u32 - 1;
+            });
+            indices.push(index);
+        }
+    }
+
+    return (vertices, indices);
+
+}
+
+/// Uploads the model (`AppData::model_path`, or the built-in triangle) into
+/// device-local vertex and index buffers.
+pub unsafe fn create_model_buffers(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let (vertices, indices) = match _data.model_path.clone() {
+        Some(path) => load_model(&path).with_context(|| format!("Failed to load {}", path.display()))?,
+        None => (TRIANGLE_VERTICES.to_vec(), TRIANGLE_INDICES.to_vec()),
+    };
+
+    // both copies go in one submission
+    let mut recorder = CommandRecorder::transfer(_data).begin(_device)?;
+    let vertex_upload = stage_upload(_device, _data, &mut recorder, "vertex buffer", &vertices, vk::BufferUsageFlags::VERTEX_BUFFER)?;
+    let index_upload = match stage_upload(_device, _data, &mut recorder, "index buffer", &indices, vk::BufferUsageFlags::INDEX_BUFFER) {
+        Ok(upload) => upload,
+        Err(error) => {
+            discard_upload(_device, _data, vertex_upload);
+            return Err(error);
+        }
+    };
+    if let Err(error) = recorder.flush() {
+        discard_upload(_device, _data, vertex_upload);
+        discard_upload(_device, _data, index_upload);
+        return Err(error);
+    }
+
+    let (buffer, allocation) = finish_upload(_device, _data, vertex_upload);
+    _data.vertex_buffer = buffer;
+    _data.vertex_allocation = Some(allocation);
+
+    let (buffer, allocation) = finish_upload(_device, _data, index_upload);
+    _data.index_buffer = buffer;
+    _data.index_allocation = Some(allocation);
+    _data.index_count = indices.len() as u32;
+
+    return Ok(());
+
+}
+
+pub unsafe fn destroy_model_buffers(_device: &Device, _data: &mut AppData) {
+
+    if let Some(allocation) = _data.vertex_allocation.take() {
+        let buffer = std::mem::take(&mut _data.vertex_buffer);
+        destroy_buffer(_device, _data, buffer, allocation);
+    }
+    if let Some(allocation) = _data.index_allocation.take() {
+        let buffer = std::mem::take(&mut _data.index_buffer);
+        destroy_buffer(_device, _data, buffer, allocation);
+    }
+    _data.index_count = 0;
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn obj_quad_shares_vertices_and_fills_defaults() {
+        // two triangles sharing an edge, no texture coordinates or normals
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3\nf 1 3 4\n";
+        let (models, _) = tobj::load_obj_buf(&mut Cursor::new(obj), &tobj::GPU_LOAD_OPTIONS, |_| {
+            return Err(tobj::LoadError::OpenFileFailed);
+        })
+        .unwrap();
+
+        let (vertices, indices) = build_model(&models);
+        assert_eq!(vertices.len(), 4);
+        assert_eq!(indices, vec![0, 1, 2, 0, 2, 3]);
+        assert!(vertices.iter().all(|v| v.color == DEFAULT_VERTEX_COLOR && v.tex_coord == Vector2::new(0.0, 0.0)));
+    }
+
+    #[test]
+    fn vertex_attributes_cover_the_vertex() {
+        let attributes = Vertex::attribute_descriptions();
+        let last = attributes[2];
+        assert_eq!(last.offset + 2 * 4, Vertex::binding_description().stride);
+        assert_eq!(size_of::<Vertex>(), 32);
+    }
+}
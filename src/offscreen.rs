@@ -0,0 +1,345 @@
+//! Render-to-texture: color targets drawn in one pass and sampled in a later
+//! one.
+
+use anyhow::Result;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::app::AppData;
+use crate::memory::{Allocation, MemoryLocation, create_image};
+use crate::handles::{track_created, track_destroyed};
+use crate::color::clear_color_value;
+
+/// Resolution of an `OffscreenTarget`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TargetSize {
+    Fixed(vk::Extent2D),
+    // follows the swapchain across resizes
+    Swapchain
+}
+
+impl TargetSize {
+    pub fn extent(self, swapchain_extent: vk::Extent2D) -> vk::Extent2D {
+        return match self {
+            Self::Fixed(extent) => extent,
+            Self::Swapchain => swapchain_extent,
+        };
+    }
+}
+
+/// A sampled color image with its own render pass and framebuffer. The pass
+/// clears the image and leaves it in `SHADER_READ_ONLY_OPTIMAL`, where a later
+/// pass reads it through `descriptor_set` (a combined image sampler at binding
+/// 0, set layout `descriptor_set_layout`). Pipelines drawing into it have to
+/// be built against `render_pass`.
+///
+/// Set `AppData::render_target` to `OffscreenTarget::new(..)` to request one;
+/// the handles are created with the swapchain objects and recreated with them.
+#[derive(Clone, Debug)]
+pub struct OffscreenTarget {
+    pub size: TargetSize,
+    pub format: vk::Format,
+    pub clear_color: [f32; 4],
+    pub extent: vk::Extent2D,
+    pub image: vk::Image,
+    pub allocation: Option<Allocation>,
+    pub view: vk::ImageView,
+    pub render_pass: vk::RenderPass,
+    pub framebuffer: vk::Framebuffer,
+    pub sampler: vk::Sampler,
+    pub descriptor_set_layout: vk::DescriptorSetLayout,
+    pub descriptor_pool: vk::DescriptorPool,
+    pub descriptor_set: vk::DescriptorSet
+}
+
+impl OffscreenTarget {
+    pub fn new(size: TargetSize, format: vk::Format) -> Self {
+        return Self {
+            size,
+            format,
+            clear_color: [0.0, 0.0, 0.0, 0.0],
+            extent: vk::Extent2D::default(),
+            image: vk::Image::null(),
+            allocation: None,
+            view: vk::ImageView::null(),
+            render_pass: vk::RenderPass::null(),
+            framebuffer: vk::Framebuffer::null(),
+            sampler: vk::Sampler::null(),
+            descriptor_set_layout: vk::DescriptorSetLayout::null(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            descriptor_set: vk::DescriptorSet::null()
+        };
+    }
+
+    /// Begins the target's render pass in `command_buffer`, covering the whole
+    /// image with the dynamic viewport and scissor set to match.
+    pub unsafe fn begin(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        let render_area = vk::Rect2D::builder()
+        .offset(vk::Offset2D::default())
+        .extent(self.extent);
+
+        let clear_values = &[vk::ClearValue { color: clear_color_value(self.clear_color, self.format) }];
+        let info = vk::RenderPassBeginInfo::builder()
+        .render_pass(self.render_pass)
+        .framebuffer(self.framebuffer)
+        .render_area(render_area)
+        .clear_values(clear_values);
+
+        device.cmd_begin_render_pass(command_buffer, &info, vk::SubpassContents::INLINE);
+
+        let viewport = vk::Viewport::builder()
+        .x(0.0)
+        .y(0.0)
+        .width(self.extent.width as f32)
+        .height(self.extent.height as f32)
+        .min_depth(0.0)
+        .max_depth(1.0);
+
+        device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+        device.cmd_set_scissor(command_buffer, 0, &[render_area]);
+    }
+
+    /// Ends the render pass; its dependency makes the image visible to
+    /// fragment shaders recorded afterwards.
+    pub unsafe fn end(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        device.cmd_end_render_pass(command_buffer);
+    }
+}
+
+/// Creates the handles of the requested `AppData::render_target`, if any, at
+/// its resolution for the current swapchain extent.
+pub unsafe fn create_render_target(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let Some(mut target) = _data.render_target.take() else {
+        return Ok(());
+    };
+
+    // handed back even on failure so destroy_render_target finds what exists
+    let result = create_render_target_objects(_device, _data, &mut target);
+    _data.render_target = Some(target);
+
+    return result;
+
+}
+
+unsafe fn create_render_target_objects(_device: &Device, _data: &mut AppData, _target: &mut OffscreenTarget) -> Result<()> {
+
+    _target.extent = _target.size.extent(_data.swapchain_extent);
+
+    let (image, allocation) = create_image(
+        _device,
+        _data,
+        "offscreen color target",
+        _target.extent,
+        1,
+        _target.format,
+        vk::SampleCountFlags::_1,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        MemoryLocation::GpuOnly,
+    )?;
+    _target.image = image;
+    _target.allocation = Some(allocation);
+
+    let subresource_range = vk::ImageSubresourceRange::builder()
+    .aspect_mask(vk::ImageAspectFlags::COLOR)
+    .base_mip_level(0)
+    .level_count(1)
+    .base_array_layer(0)
+    .layer_count(1);
+
+    let info = vk::ImageViewCreateInfo::builder()
+    .image(image)
+    .view_type(vk::ImageViewType::_2D)
+    .format(_target.format)
+    .subresource_range(subresource_range);
+
+    _target.view = _device.create_image_view(&info, None)?;
+    track_created("ImageView");
+
+    let color_attachment = vk::AttachmentDescription::builder()
+    .format(_target.format)
+    .samples(vk::SampleCountFlags::_1)
+    .load_op(vk::AttachmentLoadOp::CLEAR)
+    .store_op(vk::AttachmentStoreOp::STORE)
+    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+    .initial_layout(vk::ImageLayout::UNDEFINED)
+    .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+
+    let color_attachment_ref = vk::AttachmentReference::builder()
+    .attachment(0)
+    .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+    let color_attachments = &[color_attachment_ref];
+    let subpass = vk::SubpassDescription::builder()
+    .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+    .color_attachments(color_attachments);
+
+    // the previous frame's reads finish before the clear overwrites the image,
+    // and this frame's writes before anything samples it
+    let before = vk::SubpassDependency::builder()
+    .src_subpass(vk::SUBPASS_EXTERNAL)
+    .dst_subpass(0)
+    .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+    .src_access_mask(vk::AccessFlags::SHADER_READ)
+    .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+    .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+    let after = vk::SubpassDependency::builder()
+    .src_subpass(0)
+    .dst_subpass(vk::SUBPASS_EXTERNAL)
+    .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+    .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+    .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+    .dst_access_mask(vk::AccessFlags::SHADER_READ);
+
+    let attachments = &[color_attachment];
+    let subpasses = &[subpass];
+    let dependencies = &[before, after];
+    let info = vk::RenderPassCreateInfo::builder()
+    .attachments(attachments)
+    .subpasses(subpasses)
+    .dependencies(dependencies);
+
+    _target.render_pass = _device.create_render_pass(&info, None)?;
+    track_created("RenderPass");
+
+    let attachments = &[_target.view];
+    let info = vk::FramebufferCreateInfo::builder()
+    .render_pass(_target.render_pass)
+    .attachments(attachments)
+    .width(_target.extent.width)
+    .height(_target.extent.height)
+    .layers(1);
+
+    _target.framebuffer = _device.create_framebuffer(&info, None)?;
+    track_created("Framebuffer");
+
+    // read back one texel per pixel, so no mips or anisotropy
+    let info = vk::SamplerCreateInfo::builder()
+    .mag_filter(vk::Filter::LINEAR)
+    .min_filter(vk::Filter::LINEAR)
+    .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+    .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+    .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+    .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+    .max_lod(0.0);
+
+    _target.sampler = _device.create_sampler(&info, None)?;
+    track_created("Sampler");
+
+    let binding = vk::DescriptorSetLayoutBinding::builder()
+    .binding(0)
+    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+    .descriptor_count(1)
+    .stage_flags(vk::ShaderStageFlags::FRAGMENT);
+
+    let bindings = &[binding];
+    let info = vk::DescriptorSetLayoutCreateInfo::builder()
+    .bindings(bindings);
+
+    _target.descriptor_set_layout = _device.create_descriptor_set_layout(&info, None)?;
+    track_created("DescriptorSetLayout");
+
+    let pool_size = vk::DescriptorPoolSize::builder()
+    .type_(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+    .descriptor_count(1);
+
+    let pool_sizes = &[pool_size];
+    let info = vk::DescriptorPoolCreateInfo::builder()
+    .pool_sizes(pool_sizes)
+    .max_sets(1);
+
+    _target.descriptor_pool = _device.create_descriptor_pool(&info, None)?;
+    track_created("DescriptorPool");
+
+    let layouts = &[_target.descriptor_set_layout];
+    let info = vk::DescriptorSetAllocateInfo::builder()
+    .descriptor_pool(_target.descriptor_pool)
+    .set_layouts(layouts);
+
+    _target.descriptor_set = _device.allocate_descriptor_sets(&info)?[0];
+
+    let info = vk::DescriptorImageInfo::builder()
+    .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+    .image_view(_target.view)
+    .sampler(_target.sampler);
+
+    let image_info = &[info];
+    let sampler_write = vk::WriteDescriptorSet::builder()
+    .dst_set(_target.descriptor_set)
+    .dst_binding(0)
+    .dst_array_element(0)
+    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+    .image_info(image_info);
+
+    _device.update_descriptor_sets(&[sampler_write], &[] as &[vk::CopyDescriptorSet]);
+
+    return Ok(());
+
+}
+
+/// Destroys the render target's handles in reverse creation order, keeping
+/// the request so the next `create_render_target` rebuilds it. The GPU must be
+/// done with it.
+pub unsafe fn destroy_render_target(_device: &Device, _data: &mut AppData) {
+
+    let Some(target) = _data.render_target.as_mut() else {
+        return;
+    };
+
+    // the set goes away with the pool
+    target.descriptor_set = vk::DescriptorSet::null();
+    if !target.descriptor_pool.is_null() {
+        _device.destroy_descriptor_pool(target.descriptor_pool, None);
+        target.descriptor_pool = vk::DescriptorPool::null();
+        track_destroyed("DescriptorPool");
+    }
+    if !target.descriptor_set_layout.is_null() {
+        _device.destroy_descriptor_set_layout(target.descriptor_set_layout, None);
+        target.descriptor_set_layout = vk::DescriptorSetLayout::null();
+        track_destroyed("DescriptorSetLayout");
+    }
+    if !target.sampler.is_null() {
+        _device.destroy_sampler(target.sampler, None);
+        target.sampler = vk::Sampler::null();
+        track_destroyed("Sampler");
+    }
+    if !target.framebuffer.is_null() {
+        _device.destroy_framebuffer(target.framebuffer, None);
+        target.framebuffer = vk::Framebuffer::null();
+        track_destroyed("Framebuffer");
+    }
+    if !target.render_pass.is_null() {
+        _device.destroy_render_pass(target.render_pass, None);
+        target.render_pass = vk::RenderPass::null();
+        track_destroyed("RenderPass");
+    }
+    if !target.view.is_null() {
+        _device.destroy_image_view(target.view, None);
+        target.view = vk::ImageView::null();
+        track_destroyed("ImageView");
+    }
+    if let Some(allocation) = target.allocation.take() {
+        _device.destroy_image(target.image, None);
+        target.image = vk::Image::null();
+        track_destroyed("Image");
+        _data.allocator.free(allocation);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_size_follows_the_swapchain_when_asked() {
+        let swapchain = vk::Extent2D { width: 1280, height: 720 };
+        let fixed = vk::Extent2D { width: 256, height: 256 };
+        assert_eq!(TargetSize::Swapchain.extent(swapchain), swapchain);
+        assert_eq!(TargetSize::Fixed(fixed).extent(swapchain), fixed);
+
+        let target = OffscreenTarget::new(TargetSize::Swapchain, vk::Format::R8G8B8A8_UNORM);
+        assert!(target.image.is_null() && target.allocation.is_none());
+    }
+}
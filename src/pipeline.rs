@@ -0,0 +1,722 @@
+//! Render pass, graphics and compute pipelines, and shader checks.
+
+use anyhow::{anyhow, Context, Result};
+
+use log::*;
+
+use cgmath::Matrix4;
+
+use vulkanalia::bytecode::Bytecode;
+use vulkanalia::prelude::v1_0::*;
+
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+use crate::app::AppData;
+use crate::handles::{track_created, track_destroyed};
+use crate::model::Vertex;
+
+// shader sources relative to the crate root; embedded at build time and
+// read from disk when hot-reloading
+pub const VERT_SHADER_PATH: &str = "shaders/vert.spv";
+pub const FRAG_SHADER_PATH: &str = "shaders/frag.spv";
+// fills the storage buffer at set 0, binding 0 with 2i + 1, in groups of 64
+pub const COMP_SHADER_PATH: &str = "shaders/comp.spv";
+pub const COMP_LOCAL_SIZE: u32 = 64;
+pub const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+// push constants of the graphics pipeline: the object's model matrix, pushed
+// before each draw
+pub const PUSH_CONSTANTS_SIZE: u32 = std::mem::size_of::<Matrix4<f32>>() as u32;
+
+/// Layout the render pass leaves the color attachment in: ready to present,
+/// or ready to be copied out when rendering headless.
+pub fn final_layout(_data: &AppData) -> vk::ImageLayout {
+    return match _data.headless {
+        Some(_) => vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        None => vk::ImageLayout::PRESENT_SRC_KHR,
+    };
+}
+
+/// Creates the render pass drawing into the swapchain image. With MSAA it
+/// draws into the multisampled color image instead (attachment 0) and
+/// resolves that into the swapchain image (attachment 1) at the end.
+pub unsafe fn create_render_pass(_instance: &Instance, _device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let msaa = _data.msaa_samples != vk::SampleCountFlags::_1;
+
+    let color_attachment = vk::AttachmentDescription::builder()
+    .format(_data.swapchain_format)
+    .samples(_data.msaa_samples)
+    .load_op(vk::AttachmentLoadOp::CLEAR)
+    .store_op(if msaa { vk::AttachmentStoreOp::DONT_CARE } else { vk::AttachmentStoreOp::STORE })
+    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+    .initial_layout(vk::ImageLayout::UNDEFINED)
+    .final_layout(if msaa { vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL } else { final_layout(_data) });
+
+    let resolve_attachment = vk::AttachmentDescription::builder()
+    .format(_data.swapchain_format)
+    .samples(vk::SampleCountFlags::_1)
+    .load_op(vk::AttachmentLoadOp::DONT_CARE)
+    .store_op(vk::AttachmentStoreOp::STORE)
+    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+    .initial_layout(vk::ImageLayout::UNDEFINED)
+    .final_layout(final_layout(_data));
+
+    let color_attachment_ref = vk::AttachmentReference::builder()
+    .attachment(0)
+    .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+    let resolve_attachment_ref = vk::AttachmentReference::builder()
+    .attachment(1)
+    .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+    let color_attachments = &[color_attachment_ref];
+    let resolve_attachments = &[resolve_attachment_ref];
+    let mut subpass = vk::SubpassDescription::builder()
+    .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+    .color_attachments(color_attachments);
+    if msaa {
+        subpass = subpass.resolve_attachments(resolve_attachments);
+    }
+
+    // wait for the presentation engine to release the image before writing to it
+    let dependency = vk::SubpassDependency::builder()
+    .src_subpass(vk::SUBPASS_EXTERNAL)
+    .dst_subpass(0)
+    .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+    .src_access_mask(vk::AccessFlags::empty())
+    .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+    .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+
+    let attachments = &[color_attachment, resolve_attachment];
+    let attachments = if msaa { &attachments[..] } else { &attachments[..1] };
+    let subpasses = &[subpass];
+    let dependencies = &[dependency];
+    let info = vk::RenderPassCreateInfo::builder()
+    .attachments(attachments)
+    .subpasses(subpasses)
+    .dependencies(dependencies);
+
+    _data.render_pass = _device.create_render_pass(&info, None)?;
+    track_created("RenderPass");
+
+    return Ok(());
+
+}
+
+pub fn pipeline_cache_path() -> Result<PathBuf> {
+    return Ok(std::env::current_exe()?.with_file_name("pipeline_cache.bin"));
+}
+
+/// Creates the pipeline cache, seeded from the previous run's data when that
+/// was written by the same device and driver.
+pub unsafe fn create_pipeline_cache(_instance: &Instance, _device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let properties = _instance.get_physical_device_properties(_data.physical_device);
+    let initial_data = match pipeline_cache_path().and_then(|p| Ok(std::fs::read(p)?)) {
+        Ok(bytes) if pipeline_cache_matches(&bytes, &properties) => {
+            debug!("Loaded pipeline cache ({} bytes).", bytes.len());
+            bytes
+        }
+        Ok(_) => {
+            info!("Ignoring pipeline cache from a different device or driver.");
+            Vec::new()
+        }
+        Err(_) => Vec::new(),
+    };
+
+    let info = vk::PipelineCacheCreateInfo::builder()
+    .initial_data(&initial_data);
+
+    _data.pipeline_cache = _device.create_pipeline_cache(&info, None)?;
+    track_created("PipelineCache");
+
+    return Ok(());
+
+}
+
+pub unsafe fn save_pipeline_cache(_device: &Device, _data: &AppData) -> Result<()> {
+
+    let bytes = _device.get_pipeline_cache_data(_data.pipeline_cache)?;
+    std::fs::write(pipeline_cache_path()?, &bytes)?;
+    debug!("Saved pipeline cache ({} bytes).", bytes.len());
+
+    return Ok(());
+
+}
+
+/// Checks the version one pipeline cache header (length, version, vendor and
+/// device IDs, cache UUID) against the device. Drivers should reject foreign
+/// data themselves, but not all of them do so gracefully.
+pub fn pipeline_cache_matches(_bytes: &[u8], _properties: &vk::PhysicalDeviceProperties) -> bool {
+
+    if _bytes.len() < 16 + vk::UUID_SIZE {
+        return false;
+    }
+
+    let word = |i: usize| u32::from_le_bytes(_bytes[i * 4..i * 4 + 4].try_into().unwrap());
+    let header_length = word(0) as usize;
+    let header_version = word(1);
+
+    return header_length >= 16 + vk::UUID_SIZE
+    && header_version == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+    && word(2) == _properties.vendor_id
+    && word(3) == _properties.device_id
+    && _bytes[16..16 + vk::UUID_SIZE] == _properties.pipeline_cache_uuid[..];
+
+}
+
+pub unsafe fn create_pipeline(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let vert = include_bytes!("../shaders/vert.spv");
+    let frag = include_bytes!("../shaders/frag.spv");
+
+    let (layout, pipeline, wireframe_pipeline) = build_pipeline(_device, _data, &vert[..], &frag[..])?;
+    _data.pipeline_layout = layout;
+    _data.pipeline = pipeline;
+    _data.wireframe_pipeline = wireframe_pipeline;
+
+    return Ok(());
+
+}
+
+/// Builds a pipeline layout and pipeline from SPIR-V without storing them, so
+/// a failed rebuild (shader hot-reload) leaves the current pipeline alone.
+pub unsafe fn build_pipeline(_device: &Device, _data: &AppData, _vert: &[u8], _frag: &[u8]) -> Result<(vk::PipelineLayout, vk::Pipeline, vk::Pipeline)> {
+
+    let vert_context = || format!("Failed to load {}", VERT_SHADER_PATH);
+    let frag_context = || format!("Failed to load {}", FRAG_SHADER_PATH);
+
+    check_shader_capabilities(_vert, _data).with_context(vert_context)?;
+    check_shader_capabilities(_frag, _data).with_context(frag_context)?;
+
+    let entry_points = &_data.entry_points;
+    let vert_shader_module = create_shader_module(_device, _vert, vk::ShaderStageFlags::VERTEX, &entry_points.vertex).with_context(vert_context)?;
+    let frag_shader_module = match create_shader_module(_device, _frag, vk::ShaderStageFlags::FRAGMENT, &entry_points.fragment).with_context(frag_context) {
+        Ok(module) => module,
+        Err(error) => {
+            _device.destroy_shader_module(vert_shader_module, None);
+            track_destroyed("ShaderModule");
+            return Err(error);
+        }
+    };
+
+    let result = create_pipeline_from_modules(_device, _data, vert_shader_module, frag_shader_module);
+
+    // the pipeline keeps what it needs, so the modules can go right away
+    _device.destroy_shader_module(vert_shader_module, None);
+    _device.destroy_shader_module(frag_shader_module, None);
+    track_destroyed("ShaderModule");
+    track_destroyed("ShaderModule");
+
+    return result;
+
+}
+
+pub unsafe fn create_pipeline_from_modules(
+    _device: &Device,
+    _data: &AppData,
+    _vert_shader_module: vk::ShaderModule,
+    _frag_shader_module: vk::ShaderModule,
+) -> Result<(vk::PipelineLayout, vk::Pipeline, vk::Pipeline)> {
+
+    let vert_name = entry_point_name(&_data.entry_points.vertex)?;
+    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
+    .stage(vk::ShaderStageFlags::VERTEX)
+    .module(_vert_shader_module)
+    .name(vert_name.as_bytes_with_nul());
+
+    let frag_name = entry_point_name(&_data.entry_points.fragment)?;
+    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
+    .stage(vk::ShaderStageFlags::FRAGMENT)
+    .module(_frag_shader_module)
+    .name(frag_name.as_bytes_with_nul());
+
+    let binding_descriptions = &[Vertex::binding_description()];
+    let attribute_descriptions = Vertex::attribute_descriptions();
+    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+    .vertex_binding_descriptions(binding_descriptions)
+    .vertex_attribute_descriptions(&attribute_descriptions);
+
+    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+    .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+    .primitive_restart_enable(false);
+
+    // viewport and scissor are set while recording, so the pipeline doesn't
+    // depend on the swapchain extent and survives resizes
+    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+    .viewport_count(1)
+    .scissor_count(1);
+
+    let dynamic_states = &[vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+    .dynamic_states(dynamic_states);
+
+    let rasterization_state = |polygon_mode| {
+        return vk::PipelineRasterizationStateCreateInfo::builder()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(polygon_mode)
+        .line_width(1.0)
+        .cull_mode(vk::CullModeFlags::BACK)
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .depth_bias_enable(false);
+    };
+    let fill_state = rasterization_state(vk::PolygonMode::FILL);
+    let line_state = rasterization_state(vk::PolygonMode::LINE);
+
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+    .sample_shading_enable(false)
+    .rasterization_samples(_data.msaa_samples);
+
+    let attachment = vk::PipelineColorBlendAttachmentState::builder()
+    .color_write_mask(vk::ColorComponentFlags::all())
+    .blend_enable(false);
+
+    let attachments = &[attachment];
+    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+    .logic_op_enable(false)
+    .logic_op(vk::LogicOp::COPY)
+    .attachments(attachments)
+    .blend_constants([0.0, 0.0, 0.0, 0.0]);
+
+    let push_constant_range = vk::PushConstantRange::builder()
+    .stage_flags(vk::ShaderStageFlags::VERTEX)
+    .offset(0)
+    .size(PUSH_CONSTANTS_SIZE);
+
+    let set_layouts = &[_data.descriptor_set_layout];
+    let push_constant_ranges = &[push_constant_range];
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+    .set_layouts(set_layouts)
+    .push_constant_ranges(push_constant_ranges);
+    let layout = _device.create_pipeline_layout(&layout_info, None)?;
+    track_created("PipelineLayout");
+
+    let stages = &[vert_stage, frag_stage];
+    let info = |rasterization_state| {
+        return vk::GraphicsPipelineCreateInfo::builder()
+        .stages(stages)
+        .vertex_input_state(&vertex_input_state)
+        .input_assembly_state(&input_assembly_state)
+        .viewport_state(&viewport_state)
+        .rasterization_state(rasterization_state)
+        .multisample_state(&multisample_state)
+        .color_blend_state(&color_blend_state)
+        .dynamic_state(&dynamic_state)
+        .layout(layout)
+        .render_pass(_data.render_pass)
+        .subpass(0);
+    };
+
+    // LINE polygon mode needs fillModeNonSolid; without it there's just no
+    // wireframe toggle
+    let mut infos = vec![info(&fill_state)];
+    if _data.enabled_features.fill_mode_non_solid == vk::TRUE {
+        infos.push(info(&line_state));
+    } else {
+        warn!("fillModeNonSolid is not supported; wireframe rendering is unavailable.");
+    }
+
+    let pipelines = match _device.create_graphics_pipelines(_data.pipeline_cache, &infos, None) {
+        Ok((pipelines, _)) => pipelines,
+        Err(error) => {
+            _device.destroy_pipeline_layout(layout, None);
+            track_destroyed("PipelineLayout");
+            return Err(anyhow!(error));
+        }
+    };
+    pipelines.iter().for_each(|_| track_created("Pipeline"));
+
+    let wireframe_pipeline = pipelines.get(1).copied().unwrap_or_default();
+    return Ok((layout, pipelines[0], wireframe_pipeline));
+
+}
+
+/// Builds a compute pipeline (and its layout, with `_set_layouts`) from the
+/// SPIR-V file at `_shader_path`, starting in `_entry_point`. The caller owns
+/// both and destroys them before the device.
+pub unsafe fn create_compute_pipeline(
+    _device: &Device,
+    _data: &AppData,
+    _shader_path: &Path,
+    _entry_point: &str,
+    _set_layouts: &[vk::DescriptorSetLayout],
+) -> Result<(vk::PipelineLayout, vk::Pipeline)> {
+
+    let context = || format!("Failed to load {}", _shader_path.display());
+    let bytecode = std::fs::read(_shader_path).with_context(context)?;
+    check_shader_capabilities(&bytecode, _data).with_context(context)?;
+    let name = entry_point_name(_entry_point)?;
+    let module = create_shader_module(_device, &bytecode, vk::ShaderStageFlags::COMPUTE, _entry_point).with_context(context)?;
+
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+    .stage(vk::ShaderStageFlags::COMPUTE)
+    .module(module)
+    .name(name.as_bytes_with_nul());
+
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+    .set_layouts(_set_layouts);
+    let result = _device.create_pipeline_layout(&layout_info, None)
+    .map_err(|e| anyhow!(e))
+    .and_then(|layout| {
+        track_created("PipelineLayout");
+
+        let info = vk::ComputePipelineCreateInfo::builder()
+        .stage(stage)
+        .layout(layout);
+
+        match _device.create_compute_pipelines(_data.pipeline_cache, &[info], None) {
+            Ok((pipelines, _)) => {
+                track_created("Pipeline");
+                Ok((layout, pipelines[0]))
+            }
+            Err(error) => {
+                _device.destroy_pipeline_layout(layout, None);
+                track_destroyed("PipelineLayout");
+                Err(anyhow!(error))
+            }
+        }
+    });
+
+    _device.destroy_shader_module(module, None);
+    track_destroyed("ShaderModule");
+
+    return result;
+
+}
+
+/// Wraps SPIR-V bytecode in a shader module for `_stage`, rejecting data that
+/// isn't SPIR-V or lacks the `_entry_point` for the stage up front instead of
+/// letting the driver or validation layer choke.
+pub unsafe fn create_shader_module(_device: &Device, _bytecode: &[u8], _stage: vk::ShaderStageFlags, _entry_point: &str) -> Result<vk::ShaderModule> {
+
+    let bytecode = parse_spirv(_bytecode)?;
+    check_entry_point(bytecode.code(), _stage, _entry_point)?;
+
+    let info = vk::ShaderModuleCreateInfo::builder()
+    .code_size(bytecode.code_size())
+    .code(bytecode.code());
+
+    let module = _device.create_shader_module(&info, None)?;
+    track_created("ShaderModule");
+
+    return Ok(module);
+
+}
+
+/// What a SPIR-V capability needs from the device.
+pub enum CapabilityRequirement {
+    Feature(&'static str, fn(&vk::PhysicalDeviceFeatures) -> vk::Bool32),
+    Extension(&'static str),
+    // a feature of VK_KHR_shader_float16_int8, which the extension alone
+    // doesn't enable
+    Float16Int8Feature(&'static str, fn(&vk::PhysicalDeviceShaderFloat16Int8Features) -> vk::Bool32)
+}
+
+/// Capabilities beyond what every Vulkan 1.0 device supports, with what they
+/// need. Capabilities in neither this table nor `SPIRV_CORE_CAPABILITIES`
+/// can't be checked, so shaders declaring them are refused.
+pub const SPIRV_CAPABILITY_REQUIREMENTS: &[(u32, &str, CapabilityRequirement)] = &[
+    (2, "Geometry", CapabilityRequirement::Feature("geometryShader", |f| f.geometry_shader)),
+    (3, "Tessellation", CapabilityRequirement::Feature("tessellationShader", |f| f.tessellation_shader)),
+    (9, "Float16", CapabilityRequirement::Float16Int8Feature("shaderFloat16", |f| f.shader_float16)),
+    (10, "Float64", CapabilityRequirement::Feature("shaderFloat64", |f| f.shader_float64)),
+    (11, "Int64", CapabilityRequirement::Feature("shaderInt64", |f| f.shader_int64)),
+    (22, "Int16", CapabilityRequirement::Feature("shaderInt16", |f| f.shader_int16)),
+    (25, "ImageGatherExtended", CapabilityRequirement::Feature("shaderImageGatherExtended", |f| f.shader_image_gather_extended)),
+    (27, "StorageImageMultisample", CapabilityRequirement::Feature("shaderStorageImageMultisample", |f| f.shader_storage_image_multisample)),
+    (32, "ClipDistance", CapabilityRequirement::Feature("shaderClipDistance", |f| f.shader_clip_distance)),
+    (33, "CullDistance", CapabilityRequirement::Feature("shaderCullDistance", |f| f.shader_cull_distance)),
+    (34, "ImageCubeArray", CapabilityRequirement::Feature("imageCubeArray", |f| f.image_cube_array)),
+    (35, "SampleRateShading", CapabilityRequirement::Feature("sampleRateShading", |f| f.sample_rate_shading)),
+    (39, "Int8", CapabilityRequirement::Float16Int8Feature("shaderInt8", |f| f.shader_int8)),
+    (41, "SparseResidency", CapabilityRequirement::Feature("shaderResourceResidency", |f| f.shader_resource_residency)),
+    (42, "MinLod", CapabilityRequirement::Feature("shaderResourceMinLod", |f| f.shader_resource_min_lod)),
+    (45, "SampledCubeArray", CapabilityRequirement::Feature("imageCubeArray", |f| f.image_cube_array)),
+    (49, "StorageImageExtendedFormats", CapabilityRequirement::Feature("shaderStorageImageExtendedFormats", |f| f.shader_storage_image_extended_formats)),
+    (55, "StorageImageReadWithoutFormat", CapabilityRequirement::Feature("shaderStorageImageReadWithoutFormat", |f| f.shader_storage_image_read_without_format)),
+    (56, "StorageImageWriteWithoutFormat", CapabilityRequirement::Feature("shaderStorageImageWriteWithoutFormat", |f| f.shader_storage_image_write_without_format)),
+    (57, "MultiViewport", CapabilityRequirement::Feature("multiViewport", |f| f.multi_viewport)),
+];
+
+// capabilities every Vulkan 1.0 implementation supports without a feature
+pub const SPIRV_CORE_CAPABILITIES: &[u32] = &[0, 1, 40, 43, 44, 46, 47, 50, 51];
+
+pub const SPIRV_OP_CAPABILITY: u32 = 17;
+pub const SPIRV_OP_ENTRY_POINT: u32 = 15;
+
+/// Shader functions the graphics pipeline starts in; "main" unless the
+/// shaders were compiled with another entry point (`--vertex-entry`,
+/// `--fragment-entry`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryPoints {
+    pub vertex: String,
+    pub fragment: String
+}
+
+impl Default for EntryPoints {
+    fn default() -> Self {
+        return Self { vertex: "main".to_string(), fragment: "main".to_string() };
+    }
+}
+
+/// The SPIR-V execution model a shader stage's entry point is declared with.
+pub fn execution_model(_stage: vk::ShaderStageFlags) -> Option<u32> {
+    return match _stage {
+        vk::ShaderStageFlags::VERTEX => Some(0),
+        vk::ShaderStageFlags::TESSELLATION_CONTROL => Some(1),
+        vk::ShaderStageFlags::TESSELLATION_EVALUATION => Some(2),
+        vk::ShaderStageFlags::GEOMETRY => Some(3),
+        vk::ShaderStageFlags::FRAGMENT => Some(4),
+        vk::ShaderStageFlags::COMPUTE => Some(5),
+        _ => None,
+    };
+}
+
+/// Collects the execution model and name of every `OpEntryPoint`.
+pub fn spirv_entry_points(_code: &[u32]) -> Vec<(u32, String)> {
+
+    let mut entry_points = Vec::new();
+
+    let mut offset = 5;
+    while offset < _code.len() {
+        let word_count = (_code[offset] >> 16) as usize;
+        let opcode = _code[offset] & 0xffff;
+        if word_count == 0 || offset + word_count > _code.len() {
+            break;
+        }
+        // model, function id, then the name as a NUL-terminated UTF-8 literal
+        if opcode == SPIRV_OP_ENTRY_POINT && word_count > 3 {
+            let bytes = _code[offset + 3..offset + word_count]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .take_while(|&b| b != 0)
+            .collect::<Vec<_>>();
+            entry_points.push((_code[offset + 1], String::from_utf8_lossy(&bytes).into_owned()));
+        }
+        offset += word_count;
+    }
+
+    return entry_points;
+
+}
+
+/// Fails unless the module has an entry point called `_name` for `_stage`,
+/// listing the ones it does have.
+pub fn check_entry_point(_code: &[u32], _stage: vk::ShaderStageFlags, _name: &str) -> Result<()> {
+
+    let model = execution_model(_stage).ok_or_else(|| anyhow!("{:?} is not a single shader stage.", _stage))?;
+    let entry_points = spirv_entry_points(_code);
+    if entry_points.iter().any(|(m, n)| *m == model && n == _name) {
+        return Ok(());
+    }
+
+    let found = entry_points
+    .iter()
+    .map(|(m, n)| format!("{:?} (execution model {})", n, m))
+    .collect::<Vec<_>>();
+    return Err(anyhow!(
+        "The shader has no {:?} entry point named {:?} (execution model {}); it declares {}.",
+        _stage,
+        _name,
+        model,
+        if found.is_empty() { "none".to_string() } else { found.join(", ") },
+    ));
+
+}
+
+/// Collects the operands of every `OpCapability` instruction in a module,
+/// failing on an instruction that runs past the end.
+pub fn spirv_capabilities(_code: &[u32]) -> Result<Vec<u32>> {
+
+    let mut capabilities = Vec::new();
+
+    // five header words, then instructions of (word count << 16 | opcode)
+    let mut offset = 5;
+    while offset < _code.len() {
+        let word_count = (_code[offset] >> 16) as usize;
+        let opcode = _code[offset] & 0xffff;
+        if word_count == 0 {
+            break;
+        }
+        if offset + word_count > _code.len() {
+            return Err(anyhow!(
+                "Invalid SPIR-V bytecode: the instruction at word {} is {} words long, past the end at {}.",
+                offset,
+                word_count,
+                _code.len(),
+            ));
+        }
+        if opcode == SPIRV_OP_CAPABILITY && word_count > 1 {
+            capabilities.push(_code[offset + 1]);
+        }
+        offset += word_count;
+    }
+
+    return Ok(capabilities);
+
+}
+
+/// Returns a description of every capability the module declares that the
+/// enabled features or extensions don't cover, or that isn't known.
+pub fn unmet_spirv_capabilities(
+    _capabilities: &[u32],
+    _features: &vk::PhysicalDeviceFeatures,
+    _float16_int8: &vk::PhysicalDeviceShaderFloat16Int8Features,
+    _extensions: &[String],
+) -> Vec<String> {
+
+    let mut unmet = Vec::new();
+    for capability in _capabilities {
+        if SPIRV_CORE_CAPABILITIES.contains(capability) {
+            continue;
+        }
+
+        match SPIRV_CAPABILITY_REQUIREMENTS.iter().find(|(c, _, _)| c == capability) {
+            Some((_, name, CapabilityRequirement::Feature(feature, enabled))) => {
+                if enabled(_features) != vk::TRUE {
+                    unmet.push(format!("{} (needs the {} feature)", name, feature));
+                }
+            }
+            Some((_, name, CapabilityRequirement::Extension(extension))) => {
+                if !_extensions.iter().any(|e| e == extension) {
+                    unmet.push(format!("{} (needs {})", name, extension));
+                }
+            }
+            Some((_, name, CapabilityRequirement::Float16Int8Feature(feature, enabled))) => {
+                if enabled(_float16_int8) != vk::TRUE {
+                    unmet.push(format!("{} (needs the {} feature of VK_KHR_shader_float16_int8)", name, feature));
+                }
+            }
+            None => unmet.push(format!("capability {} (unknown, so its support can't be checked)", capability)),
+        }
+    }
+
+    return unmet;
+
+}
+
+/// Refuses shaders that declare capabilities the device wasn't created with,
+/// which would otherwise fail inside the driver or validation layer.
+pub fn check_shader_capabilities(_bytecode: &[u8], _data: &AppData) -> Result<()> {
+
+    let bytecode = parse_spirv(_bytecode)?;
+    let capabilities = spirv_capabilities(bytecode.code())?;
+    let unmet = unmet_spirv_capabilities(&capabilities, &_data.enabled_features, &_data.float16_int8_features, &_data.device_extensions);
+    if !unmet.is_empty() {
+        return Err(anyhow!("Shader requires unsupported SPIR-V capabilities: {}.", unmet.join(", ")));
+    }
+
+    return Ok(());
+
+}
+
+pub fn entry_point_name(_name: &str) -> Result<CString> {
+    return CString::new(_name).map_err(|_| anyhow!("Entry point name {:?} must not contain NUL bytes.", _name));
+}
+
+pub fn parse_spirv(_bytecode: &[u8]) -> Result<Bytecode> {
+
+    if _bytecode.is_empty() || !_bytecode.len().is_multiple_of(4) {
+        return Err(anyhow!("Invalid SPIR-V bytecode: {} bytes is not a whole number of 32-bit words.", _bytecode.len()));
+    }
+    let bytecode = Bytecode::new(_bytecode).map_err(|e| anyhow!("Invalid SPIR-V bytecode: {}", e))?;
+    match bytecode.code().first() {
+        Some(&SPIRV_MAGIC) => (),
+        Some(word) => return Err(anyhow!("Invalid SPIR-V bytecode: starts with {:#010x} instead of the magic number {:#010x}.", word, SPIRV_MAGIC)),
+        None => return Err(anyhow!("Invalid SPIR-V bytecode: missing magic number.")),
+    }
+
+    return Ok(bytecode);
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spirv_is_checked_before_use() {
+        assert!(parse_spirv(include_bytes!("../shaders/vert.spv")).is_ok());
+        assert!(parse_spirv(include_bytes!("../shaders/frag.spv")).is_ok());
+        let comp = parse_spirv(include_bytes!("../shaders/comp.spv")).unwrap();
+        assert!(check_entry_point(comp.code(), vk::ShaderStageFlags::COMPUTE, "main").is_ok());
+        assert_eq!(spirv_capabilities(comp.code()).unwrap(), vec![1]);
+
+        assert!(parse_spirv(&[]).is_err());
+        assert!(parse_spirv(&[0x03, 0x02, 0x23]).is_err());
+        assert!(parse_spirv(&[0u8; 8]).is_err());
+    }
+
+    #[test]
+    fn entry_points_must_match_the_stage() {
+        let vert = parse_spirv(include_bytes!("../shaders/vert.spv")).unwrap();
+        assert_eq!(spirv_entry_points(vert.code()), vec![(0, "main".to_string())]);
+        assert!(check_entry_point(vert.code(), vk::ShaderStageFlags::VERTEX, "main").is_ok());
+
+        let wrong_stage = check_entry_point(vert.code(), vk::ShaderStageFlags::FRAGMENT, "main").unwrap_err();
+        assert!(wrong_stage.to_string().contains("\"main\" (execution model 0)"));
+        assert!(check_entry_point(vert.code(), vk::ShaderStageFlags::VERTEX, "vs_main").is_err());
+        assert!(check_entry_point(vert.code(), vk::ShaderStageFlags::ALL_GRAPHICS, "main").is_err());
+
+        assert!(parse_spirv(&[0x07, 0x23, 0x02, 0x03]).err().unwrap().to_string().contains("0x03022307"));
+    }
+
+    #[test]
+    fn pipeline_cache_header_must_match_device() {
+        let mut properties = vk::PhysicalDeviceProperties {
+            vendor_id: 0x10de,
+            device_id: 0x2204,
+            pipeline_cache_uuid: vk::ByteArray([7; vk::UUID_SIZE]),
+            ..Default::default()
+        };
+
+        let mut header = Vec::new();
+        for word in [32u32, 1, 0x10de, 0x2204] {
+            header.extend_from_slice(&word.to_le_bytes());
+        }
+        header.extend_from_slice(&[7; vk::UUID_SIZE]);
+        assert!(pipeline_cache_matches(&header, &properties));
+
+        properties.device_id = 0x2206;
+        assert!(!pipeline_cache_matches(&header, &properties));
+        assert!(!pipeline_cache_matches(&header[..20], &properties));
+    }
+
+    #[test]
+    fn shader_capabilities_are_checked_against_features() {
+        let vert = parse_spirv(include_bytes!("../shaders/vert.spv")).unwrap();
+        assert_eq!(spirv_capabilities(vert.code()).unwrap(), vec![1]);
+
+        // an OpCapability claiming more words than are left
+        let mut truncated = vert.code()[..5].to_vec();
+        truncated.push((4 << 16) | SPIRV_OP_CAPABILITY);
+        truncated.push(1);
+        assert!(spirv_capabilities(&truncated).is_err());
+
+        let features = vk::PhysicalDeviceFeatures::default();
+        let float16_int8 = vk::PhysicalDeviceShaderFloat16Int8Features::default();
+        assert!(unmet_spirv_capabilities(&[1], &features, &float16_int8, &[]).is_empty());
+        assert_eq!(unmet_spirv_capabilities(&[2, 4448], &features, &float16_int8, &[]).len(), 2);
+
+        let features = vk::PhysicalDeviceFeatures { geometry_shader: vk::TRUE, ..Default::default() };
+        assert!(unmet_spirv_capabilities(&[2], &features, &float16_int8, &[]).is_empty());
+
+        // StorageBuffer8BitAccess needs a feature that's never enabled, so the
+        // extension alone isn't enough
+        let extensions = ["VK_KHR_8bit_storage".to_string()];
+        assert_eq!(unmet_spirv_capabilities(&[2, 4448], &features, &float16_int8, &extensions).len(), 1);
+
+        // the extension alone doesn't enable Float16 or Int8
+        let extensions = ["VK_KHR_shader_float16_int8".to_string()];
+        assert_eq!(unmet_spirv_capabilities(&[9, 39], &features, &float16_int8, &extensions).len(), 2);
+        let float16_int8 = vk::PhysicalDeviceShaderFloat16Int8Features { shader_float16: vk::TRUE, ..Default::default() };
+        assert_eq!(unmet_spirv_capabilities(&[9, 39], &features, &float16_int8, &extensions).len(), 1);
+
+        assert_eq!(unmet_spirv_capabilities(&[1, 123456], &features, &float16_int8, &[]).len(), 1);
+    }
+}
@@ -0,0 +1,61 @@
+//! Ctrl+C and termination requests, turned into a normal event loop exit so
+//! `ApplicationHandler::exiting` still tears everything down.
+
+use anyhow::{anyhow, Result};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// set from the signal handler, which may do nothing more than this
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_signal(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Routes SIGINT and SIGTERM to `interrupted` instead of killing the process.
+/// Installing it again is harmless.
+#[cfg(unix)]
+pub fn install_interrupt_handler() -> Result<()> {
+
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(anyhow!("Failed to install a handler for signal {}: {}", signal, std::io::Error::last_os_error()));
+        }
+    }
+
+    return Ok(());
+
+}
+
+#[cfg(not(unix))]
+pub fn install_interrupt_handler() -> Result<()> {
+
+    return Err(anyhow!("Interrupt handling is only implemented for Unix."));
+
+}
+
+/// Whether a shutdown was requested by a signal. Stays set, so every check
+/// after the first sees it too.
+pub fn interrupted() -> bool {
+    return INTERRUPTED.load(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn signals_set_the_interrupt_flag() {
+        install_interrupt_handler().unwrap();
+        install_interrupt_handler().unwrap();
+        assert!(!interrupted());
+
+        // handled synchronously by the raising thread
+        assert_eq!(unsafe { libc::raise(libc::SIGINT) }, 0);
+        assert!(interrupted());
+        assert!(interrupted());
+    }
+}
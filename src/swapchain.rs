@@ -0,0 +1,497 @@
+//! Swapchain creation and the choices that go into it.
+
+use anyhow::{anyhow, Result};
+
+use log::*;
+
+use winit::dpi::PhysicalSize;
+use winit::window::Window;
+
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::vk::KhrSurfaceExtension;
+use vulkanalia::vk::KhrSwapchainExtension;
+
+use crate::app::AppData;
+use crate::device::QueueFamilyIndices;
+use crate::memory::{MemoryLocation, create_image};
+use crate::handles::track_created;
+
+#[derive(Clone, Debug)]
+pub struct SwapchainSupport {
+    pub capabilities: vk::SurfaceCapabilitiesKHR,
+    pub formats: Vec<vk::SurfaceFormatKHR>,
+    pub present_modes: Vec<vk::PresentModeKHR>
+}
+
+impl SwapchainSupport {
+    pub unsafe fn get(_instance: &Instance, _data: &AppData, _p_device: vk::PhysicalDevice) -> Result<Self> {
+        return Ok(Self {
+            capabilities: _instance.get_physical_device_surface_capabilities_khr(_p_device, _data.surface)?,
+            formats: _instance.get_physical_device_surface_formats_khr(_p_device, _data.surface)?,
+            present_modes: _instance.get_physical_device_surface_present_modes_khr(_p_device, _data.surface)?
+        });
+    }
+}
+
+pub unsafe fn create_swapchain(_window: &Window, _instance: &Instance, _device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let indices = QueueFamilyIndices::get(_instance, _data, _data.physical_device)?;
+    let support = SwapchainSupport::get(_instance, _data, _data.physical_device)?;
+
+    let surface_format = choose_swapchain_surface_format(&support.formats)?;
+    let present_mode = choose_swapchain_present_mode(&support.present_modes, _data.present_mode);
+    let extent = choose_swapchain_extent(&support.capabilities, _window.inner_size());
+    let image_usage = swapchain_image_usage(&support.capabilities, _data)?;
+
+    let image_count = choose_swapchain_image_count(&support.capabilities);
+
+    let mut queue_family_indices = vec![];
+    let image_sharing_mode = if indices.graphics != indices.present {
+        queue_family_indices.push(indices.graphics);
+        queue_family_indices.push(indices.present);
+        vk::SharingMode::CONCURRENT
+    } else {
+        vk::SharingMode::EXCLUSIVE
+    };
+
+    let mut info = vk::SwapchainCreateInfoKHR::builder()
+    .surface(_data.surface)
+    .min_image_count(image_count)
+    .image_format(surface_format.format)
+    .image_color_space(surface_format.color_space)
+    .image_extent(extent)
+    .image_array_layers(1)
+    .image_usage(image_usage)
+    .image_sharing_mode(image_sharing_mode)
+    .queue_family_indices(&queue_family_indices)
+    .pre_transform(support.capabilities.current_transform)
+    .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+    .present_mode(present_mode)
+    .clipped(true)
+    .old_swapchain(vk::SwapchainKHR::null());
+
+    // some drivers misreport the image count limits; the minimum is the one
+    // count that has to work
+    _data.swapchain = match _device.create_swapchain_khr(&info, None) {
+        Err(error) if image_count != support.capabilities.min_image_count && is_out_of_memory(error) => {
+            warn!(
+                "Swapchain creation with {} images failed ({}); retrying with the minimum of {}.",
+                image_count, error, support.capabilities.min_image_count
+            );
+            info = info.min_image_count(support.capabilities.min_image_count);
+            _device.create_swapchain_khr(&info, None)?
+        }
+        result => result?,
+    };
+    track_created("SwapchainKHR");
+
+    _data.swapchain_images = _device.get_swapchain_images_khr(_data.swapchain)?;
+    _data.swapchain_format = surface_format.format;
+    _data.swapchain_extent = extent;
+    _data.swapchain_usage = image_usage;
+
+    info!(
+        "Created swapchain ({}x{}, {:?}, {:?}, {} images).",
+        extent.width, extent.height, surface_format.format, present_mode, _data.swapchain_images.len()
+    );
+
+    return Ok(());
+
+}
+
+/// Creates the single image a headless app renders to in place of the
+/// swapchain images.
+pub unsafe fn create_offscreen_target(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    let extent = _data.headless.ok_or_else(|| anyhow!("No offscreen target size."))?;
+    let format = vk::Format::R8G8B8A8_SRGB;
+    let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC;
+
+    let (image, allocation) = create_image(
+        _device,
+        _data,
+        "offscreen target",
+        extent,
+        1,
+        format,
+        vk::SampleCountFlags::_1,
+        usage,
+        MemoryLocation::GpuOnly,
+    )?;
+
+    _data.swapchain_images = vec![image];
+    _data.swapchain_format = format;
+    _data.swapchain_extent = extent;
+    _data.swapchain_usage = usage;
+    _data.offscreen_allocation = Some(allocation);
+
+    return Ok(());
+
+}
+
+/// Creates the multisampled color image the scene is drawn into before being
+/// resolved into the swapchain image. Without MSAA there is none.
+pub unsafe fn create_color_objects(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    if _data.msaa_samples == vk::SampleCountFlags::_1 {
+        return Ok(());
+    }
+
+    // only ever written and resolved within the render pass
+    let (image, allocation) = create_image(
+        _device,
+        _data,
+        "MSAA color image",
+        _data.swapchain_extent,
+        1,
+        _data.swapchain_format,
+        _data.msaa_samples,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+        MemoryLocation::GpuOnly,
+    )?;
+    _data.color_image = image;
+    _data.color_image_allocation = Some(allocation);
+
+    let subresource_range = vk::ImageSubresourceRange::builder()
+    .aspect_mask(vk::ImageAspectFlags::COLOR)
+    .base_mip_level(0)
+    .level_count(1)
+    .base_array_layer(0)
+    .layer_count(1);
+
+    let info = vk::ImageViewCreateInfo::builder()
+    .image(image)
+    .view_type(vk::ImageViewType::_2D)
+    .format(_data.swapchain_format)
+    .subresource_range(subresource_range);
+
+    _data.color_image_view = _device.create_image_view(&info, None)?;
+    track_created("ImageView");
+
+    return Ok(());
+
+}
+
+pub unsafe fn create_swapchain_image_views(_device: &Device, _data: &mut AppData) -> Result<()> {
+
+    if _data.swapchain_images.is_empty() {
+        return Err(anyhow!("The swapchain has no images."));
+    }
+
+    let components = vk::ComponentMapping::builder()
+    .r(vk::ComponentSwizzle::IDENTITY)
+    .g(vk::ComponentSwizzle::IDENTITY)
+    .b(vk::ComponentSwizzle::IDENTITY)
+    .a(vk::ComponentSwizzle::IDENTITY);
+
+    let subresource_range = vk::ImageSubresourceRange::builder()
+    .aspect_mask(vk::ImageAspectFlags::COLOR)
+    .base_mip_level(0)
+    .level_count(1)
+    .base_array_layer(0)
+    .layer_count(1);
+
+    // pushed one at a time so a failure part-way leaves the created views
+    // where destroy can find them
+    for &image in &_data.swapchain_images {
+        let info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(vk::ImageViewType::_2D)
+        .format(_data.swapchain_format)
+        .components(components)
+        .subresource_range(subresource_range);
+
+        _data.swapchain_image_views.push(_device.create_image_view(&info, None)?);
+        track_created("ImageView");
+    }
+
+    return Ok(());
+
+}
+
+/// Prefers 8-bit BGRA in the sRGB color space, otherwise takes whatever the
+/// surface lists first.
+pub fn choose_swapchain_surface_format(_formats: &[vk::SurfaceFormatKHR]) -> Result<vk::SurfaceFormatKHR> {
+
+    let preferred = _formats.iter().find(|f| {
+        f.format == vk::Format::B8G8R8A8_SRGB && f.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+    });
+
+    return preferred
+    .or_else(|| _formats.first())
+    .cloned()
+    .ok_or_else(|| anyhow!("Surface reports no supported formats."));
+
+}
+
+/// One more image than the minimum so we don't wait on the driver, clamped to
+/// the maximum (0 means unbounded) but never below the minimum, even when a
+/// driver reports a maximum smaller than it.
+pub fn choose_swapchain_image_count(_capabilities: &vk::SurfaceCapabilitiesKHR) -> u32 {
+
+    let min = _capabilities.min_image_count;
+    let max = _capabilities.max_image_count;
+
+    let mut image_count = min + 1;
+    if max != 0 && image_count > max {
+        image_count = max;
+    }
+    if image_count < min {
+        warn!("Surface reports maxImageCount {} below minImageCount {}; using the minimum.", max, min);
+        image_count = min;
+    }
+
+    return image_count;
+
+}
+
+/// Whether a failed swapchain creation is worth retrying with fewer images.
+/// Other errors (a lost surface or device, an unsupported format) aren't
+/// about the count, and a retry would only hide them.
+pub fn is_out_of_memory(_error: vk::ErrorCode) -> bool {
+    return matches!(_error, vk::ErrorCode::OUT_OF_HOST_MEMORY | vk::ErrorCode::OUT_OF_DEVICE_MEMORY);
+}
+
+/// Ways features touch the swapchain images other than rendering into them,
+/// each needing its own image usage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SwapchainAccess {
+    // screenshots copy out of the presented image (TRANSFER_SRC); dropped
+    // rather than failed over when unsupported
+    pub capture: bool,
+    // post-processing blits its result into the image (TRANSFER_DST)
+    pub blit_target: bool,
+    // compute shaders write the image directly (STORAGE)
+    pub compute_target: bool
+}
+
+impl Default for SwapchainAccess {
+    fn default() -> Self {
+        return Self { capture: true, blit_target: false, compute_target: false };
+    }
+}
+
+/// Usage the swapchain images need for the features in
+/// `AppData::swapchain_access`, checked against what the surface supports so
+/// a missing flag fails here with a clear error rather than as a validation
+/// error later.
+pub fn swapchain_image_usage(_capabilities: &vk::SurfaceCapabilitiesKHR, _data: &AppData) -> Result<vk::ImageUsageFlags> {
+
+    let access = _data.swapchain_access;
+    let mut required = vk::ImageUsageFlags::COLOR_ATTACHMENT;
+    if access.blit_target {
+        required |= vk::ImageUsageFlags::TRANSFER_DST;
+    }
+    if access.compute_target {
+        required |= vk::ImageUsageFlags::STORAGE;
+    }
+
+    let missing = required & !_capabilities.supported_usage_flags;
+    if !missing.is_empty() {
+        return Err(anyhow!("The surface doesn't support swapchain image usage {:?}.", missing));
+    }
+
+    let mut optional = vk::ImageUsageFlags::empty();
+    if access.capture {
+        optional |= vk::ImageUsageFlags::TRANSFER_SRC;
+    }
+
+    return Ok(required | (optional & _capabilities.supported_usage_flags));
+
+}
+
+/// How frames are queued for display: FIFO waits for vblank (vsync), MAILBOX
+/// replaces queued frames for low latency without tearing, IMMEDIATE may tear.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PresentMode {
+    #[default]
+    Fifo,
+    Mailbox,
+    Immediate
+}
+
+impl PresentMode {
+    pub fn parse(_mode: &str) -> Result<Self> {
+        return match _mode.to_ascii_lowercase().as_str() {
+            "fifo" | "vsync" => Ok(Self::Fifo),
+            "mailbox" => Ok(Self::Mailbox),
+            "immediate" => Ok(Self::Immediate),
+            _ => Err(anyhow!("Unknown present mode '{}', expected fifo, mailbox or immediate.", _mode)),
+        };
+    }
+
+    pub fn to_vk(self) -> vk::PresentModeKHR {
+        return match self {
+            Self::Fifo => vk::PresentModeKHR::FIFO,
+            Self::Mailbox => vk::PresentModeKHR::MAILBOX,
+            Self::Immediate => vk::PresentModeKHR::IMMEDIATE,
+        };
+    }
+}
+
+pub fn choose_swapchain_present_mode(_supported: &[vk::PresentModeKHR], _preferred: PresentMode) -> vk::PresentModeKHR {
+
+    let preferred = _preferred.to_vk();
+    if _supported.contains(&preferred) {
+        return preferred;
+    }
+
+    // FIFO is the only mode every implementation is required to support
+    if _preferred != PresentMode::Fifo {
+        warn!("Present mode {:?} is not supported by the surface; falling back to FIFO.", preferred);
+    }
+    return vk::PresentModeKHR::FIFO;
+
+}
+
+/// The present mode to switch to when turning vsync off: IMMEDIATE, which
+/// doesn't wait at all, or else MAILBOX. None when the surface only does FIFO.
+pub fn vsync_off_mode(_supported: &[vk::PresentModeKHR]) -> Option<PresentMode> {
+    return [PresentMode::Immediate, PresentMode::Mailbox]
+    .into_iter()
+    .find(|mode| _supported.contains(&mode.to_vk()));
+}
+
+/// Picks the swapchain extent for a window of the given physical size.
+///
+/// A `current_extent.width` of `u32::MAX` means the surface lets us choose,
+/// in which case the window size is clamped to the supported range.
+pub fn choose_swapchain_extent(_capabilities: &vk::SurfaceCapabilitiesKHR, _window_size: PhysicalSize<u32>) -> vk::Extent2D {
+
+    if _capabilities.current_extent.width != u32::MAX {
+        return _capabilities.current_extent;
+    }
+
+    let min = _capabilities.min_image_extent;
+    let max = _capabilities.max_image_extent;
+
+    return vk::Extent2D::builder()
+    .width(_window_size.width.clamp(min.width, max.width))
+    .height(_window_size.height.clamp(min.height, max.height))
+    .build();
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(current: (u32, u32), min: (u32, u32), max: (u32, u32)) -> vk::SurfaceCapabilitiesKHR {
+        return vk::SurfaceCapabilitiesKHR::builder()
+        .current_extent(vk::Extent2D { width: current.0, height: current.1 })
+        .min_image_extent(vk::Extent2D { width: min.0, height: min.1 })
+        .max_image_extent(vk::Extent2D { width: max.0, height: max.1 })
+        .build();
+    }
+
+    #[test]
+    fn extent_uses_current_extent_when_fixed() {
+        let caps = capabilities((800, 600), (1, 1), (4096, 4096));
+        let extent = choose_swapchain_extent(&caps, PhysicalSize::new(1280, 720));
+        assert_eq!((extent.width, extent.height), (800, 600));
+    }
+
+    #[test]
+    fn extent_clamps_too_large_window() {
+        let caps = capabilities((u32::MAX, u32::MAX), (1, 1), (1920, 1080));
+        let extent = choose_swapchain_extent(&caps, PhysicalSize::new(3840, 2160));
+        assert_eq!((extent.width, extent.height), (1920, 1080));
+    }
+
+    #[test]
+    fn extent_clamps_too_small_window() {
+        let caps = capabilities((u32::MAX, u32::MAX), (64, 48), (1920, 1080));
+        let extent = choose_swapchain_extent(&caps, PhysicalSize::new(10, 0));
+        assert_eq!((extent.width, extent.height), (64, 48));
+    }
+
+    #[test]
+    fn extent_keeps_window_size_in_range() {
+        let caps = capabilities((u32::MAX, u32::MAX), (1, 1), (4096, 4096));
+        let extent = choose_swapchain_extent(&caps, PhysicalSize::new(1280, 720));
+        assert_eq!((extent.width, extent.height), (1280, 720));
+    }
+
+    fn surface_format(format: vk::Format) -> vk::SurfaceFormatKHR {
+        return vk::SurfaceFormatKHR { format, color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR };
+    }
+
+    #[test]
+    fn surface_format_prefers_srgb_bgra() {
+        let formats = [surface_format(vk::Format::R8G8B8A8_UNORM), surface_format(vk::Format::B8G8R8A8_SRGB)];
+        assert_eq!(choose_swapchain_surface_format(&formats).unwrap().format, vk::Format::B8G8R8A8_SRGB);
+
+        let formats = [surface_format(vk::Format::R8G8B8A8_UNORM)];
+        assert_eq!(choose_swapchain_surface_format(&formats).unwrap().format, vk::Format::R8G8B8A8_UNORM);
+
+        assert!(choose_swapchain_surface_format(&[]).is_err());
+    }
+
+    #[test]
+    fn present_mode_falls_back_to_fifo() {
+        let supported = [vk::PresentModeKHR::FIFO, vk::PresentModeKHR::MAILBOX];
+        assert_eq!(choose_swapchain_present_mode(&supported, PresentMode::Mailbox), vk::PresentModeKHR::MAILBOX);
+        assert_eq!(choose_swapchain_present_mode(&supported, PresentMode::Immediate), vk::PresentModeKHR::FIFO);
+        assert!(PresentMode::parse("adaptive").is_err());
+    }
+
+    #[test]
+    fn vsync_off_prefers_immediate() {
+        use vk::PresentModeKHR as M;
+        assert_eq!(vsync_off_mode(&[M::FIFO, M::MAILBOX, M::IMMEDIATE]), Some(PresentMode::Immediate));
+        assert_eq!(vsync_off_mode(&[M::FIFO, M::MAILBOX]), Some(PresentMode::Mailbox));
+        assert_eq!(vsync_off_mode(&[M::FIFO, M::FIFO_RELAXED]), None);
+    }
+
+    #[test]
+    fn swapchain_usage_must_be_supported() {
+        let mut caps = capabilities((800, 600), (1, 1), (4096, 4096));
+        caps.supported_usage_flags = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_DST;
+        assert_eq!(swapchain_image_usage(&caps, &AppData::default()).unwrap(), vk::ImageUsageFlags::COLOR_ATTACHMENT);
+
+        caps.supported_usage_flags |= vk::ImageUsageFlags::TRANSFER_SRC;
+        assert!(swapchain_image_usage(&caps, &AppData::default()).unwrap().contains(vk::ImageUsageFlags::TRANSFER_SRC));
+
+        caps.supported_usage_flags = vk::ImageUsageFlags::TRANSFER_DST;
+        assert!(swapchain_image_usage(&caps, &AppData::default()).is_err());
+    }
+
+    #[test]
+    fn only_memory_errors_retry_with_fewer_images() {
+        assert!(is_out_of_memory(vk::ErrorCode::OUT_OF_DEVICE_MEMORY));
+        assert!(is_out_of_memory(vk::ErrorCode::OUT_OF_HOST_MEMORY));
+        assert!(!is_out_of_memory(vk::ErrorCode::SURFACE_LOST_KHR));
+        assert!(!is_out_of_memory(vk::ErrorCode::DEVICE_LOST));
+    }
+
+    #[test]
+    fn swapchain_usage_follows_enabled_access() {
+        let mut caps = capabilities((800, 600), (1, 1), (4096, 4096));
+        caps.supported_usage_flags = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST;
+        let mut data = AppData::default();
+        data.swapchain_access.capture = false;
+        assert_eq!(swapchain_image_usage(&caps, &data).unwrap(), vk::ImageUsageFlags::COLOR_ATTACHMENT);
+
+        data.swapchain_access.blit_target = true;
+        assert!(swapchain_image_usage(&caps, &data).unwrap().contains(vk::ImageUsageFlags::TRANSFER_DST));
+
+        // storage writes can't be dropped like capture can
+        data.swapchain_access.compute_target = true;
+        assert!(swapchain_image_usage(&caps, &data).is_err());
+    }
+
+    #[test]
+    fn image_count_stays_within_reported_limits() {
+        let mut caps = capabilities((800, 600), (1, 1), (4096, 4096));
+        caps.min_image_count = 2;
+        caps.max_image_count = 0;
+        assert_eq!(choose_swapchain_image_count(&caps), 3);
+
+        caps.max_image_count = 2;
+        assert_eq!(choose_swapchain_image_count(&caps), 2);
+
+        // misreported maximum below the minimum
+        caps.max_image_count = 1;
+        assert_eq!(choose_swapchain_image_count(&caps), 2);
+    }
+}
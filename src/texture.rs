@@ -0,0 +1,152 @@
+//! Texture helpers: mip chain sizing and GPU mipmap generation.
+
+use anyhow::{anyhow, Result};
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::app::AppData;
+use crate::commands::CommandRecorder;
+
+/// Levels of a full mip chain down to 1x1: `floor(log2(max(w, h))) + 1`.
+pub fn mip_levels(_width: u32, _height: u32) -> u32 {
+    return _width.max(_height).max(1).ilog2() + 1;
+}
+
+/// Checks that `_format` can be blitted from and to and filtered linearly with
+/// optimal tiling, which `generate_mipmaps` relies on for its blits.
+pub unsafe fn check_linear_blit(_instance: &Instance, _data: &AppData, _format: vk::Format) -> Result<()> {
+
+    let properties = _instance.get_physical_device_format_properties(_data.physical_device, _format);
+    let required = vk::FormatFeatureFlags::BLIT_SRC
+        | vk::FormatFeatureFlags::BLIT_DST
+        | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+    if !properties.optimal_tiling_features.contains(required) {
+        return Err(anyhow!(
+            "{:?} does not support BLIT_SRC, BLIT_DST and linear filtering, so its mipmaps can't be generated by blitting.",
+            _format,
+        ));
+    }
+
+    return Ok(());
+
+}
+
+/// Fills mip levels 1.. of `_image` by repeatedly blitting each level into the
+/// next at half the size, then leaves every level shader-readable. All levels
+/// must be in `TRANSFER_DST_OPTIMAL` with level 0 holding the texture, and the
+/// image needs `TRANSFER_SRC` and `TRANSFER_DST` usage.
+pub unsafe fn generate_mipmaps(
+    _instance: &Instance,
+    _device: &Device,
+    _data: &AppData,
+    _image: vk::Image,
+    _format: vk::Format,
+    _width: u32,
+    _height: u32,
+    _mip_levels: u32,
+) -> Result<()> {
+
+    if _mip_levels == 0 {
+        return Err(anyhow!("An image has at least one mip level."));
+    }
+    check_linear_blit(_instance, _data, _format)?;
+
+    // blits need a graphics queue
+    let mut recorder = CommandRecorder::graphics(_data).begin(_device)?;
+
+    let barrier = |level: u32, old_layout, new_layout, src_access_mask, dst_access_mask| {
+        let subresource = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(level)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+
+        return vk::ImageMemoryBarrier::builder()
+        .image(_image)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .subresource_range(subresource)
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask)
+        .build();
+    };
+
+    let (mut width, mut height) = (_width as i32, _height as i32);
+    for level in 1.._mip_levels {
+        // the previous level is complete once it's been written (by the
+        // upload or the last blit); read it as the blit source
+        let to_source = barrier(
+            level - 1,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::TRANSFER_READ,
+        );
+        recorder.image_barrier(to_source, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::TRANSFER);
+
+        let (next_width, next_height) = ((width / 2).max(1), (height / 2).max(1));
+        let subresource = |level| {
+            return vk::ImageSubresourceLayers::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(level)
+            .base_array_layer(0)
+            .layer_count(1);
+        };
+        let blit = vk::ImageBlit::builder()
+        .src_offsets([vk::Offset3D { x: 0, y: 0, z: 0 }, vk::Offset3D { x: width, y: height, z: 1 }])
+        .src_subresource(subresource(level - 1))
+        .dst_offsets([vk::Offset3D { x: 0, y: 0, z: 0 }, vk::Offset3D { x: next_width, y: next_height, z: 1 }])
+        .dst_subresource(subresource(level));
+
+        _device.cmd_blit_image(
+            recorder.record(),
+            _image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            _image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[blit],
+            vk::Filter::LINEAR,
+        );
+
+        let to_shader = barrier(
+            level - 1,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::AccessFlags::TRANSFER_READ,
+            vk::AccessFlags::SHADER_READ,
+        );
+        recorder.image_barrier(to_shader, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::FRAGMENT_SHADER);
+
+        (width, height) = (next_width, next_height);
+    }
+
+    // the last level was only ever blitted into
+    let last = barrier(
+        _mip_levels - 1,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        vk::AccessFlags::TRANSFER_WRITE,
+        vk::AccessFlags::SHADER_READ,
+    );
+    recorder.image_barrier(last, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::FRAGMENT_SHADER);
+
+    return recorder.flush();
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mip_chain_reaches_one_pixel() {
+        assert_eq!(mip_levels(1, 1), 1);
+        assert_eq!(mip_levels(2, 1), 2);
+        assert_eq!(mip_levels(512, 512), 10);
+        assert_eq!(mip_levels(1024, 600), 11);
+        assert_eq!(mip_levels(1023, 7), 10);
+    }
+}